@@ -0,0 +1,3 @@
+-- Entry and scan error paths become relative to their manifest's root.
+-- The rewrite itself is done by Database::relativize_paths, because roots
+-- are compared by path component rather than by byte prefix.
@@ -0,0 +1,2 @@
+ALTER TABLE manifest ADD COLUMN hash_algorithms TEXT NOT NULL DEFAULT 'blake3';
+ALTER TABLE entry ADD COLUMN digests TEXT;
@@ -0,0 +1,10 @@
+CREATE TABLE watch_change (
+	id INTEGER PRIMARY KEY,
+	manifest_id INTEGER NOT NULL,
+	timestamp INTEGER NOT NULL,
+	type TEXT NOT NULL,
+	file_path BLOB NOT NULL,
+	hash TEXT,
+	FOREIGN KEY (manifest_id) REFERENCES manifest (id)
+);
+CREATE INDEX watch_change_manifest_id ON watch_change (manifest_id);
@@ -0,0 +1 @@
+ALTER TABLE manifest ADD COLUMN scheduled INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,21 @@
+CREATE TABLE manifest_new (
+	id INTEGER PRIMARY KEY,
+	timestamp INTEGER NOT NULL,
+	directory_path TEXT NOT NULL
+);
+INSERT INTO manifest_new (id, timestamp, directory_path)
+	SELECT id, timestamp, directory_path FROM manifest;
+DROP TABLE manifest;
+ALTER TABLE manifest_new RENAME TO manifest;
+CREATE TABLE entry (
+	id INTEGER PRIMARY KEY,
+	manifest_id INTEGER NOT NULL,
+	file_path TEXT NOT NULL,
+	hash TEXT NOT NULL,
+	created TEXT,
+	modified TEXT,
+	accessed TEXT,
+	FOREIGN KEY (manifest_id) REFERENCES manifest (id)
+);
+CREATE INDEX entry_manifest_id_file_path ON entry (manifest_id, file_path);
+CREATE INDEX entry_hash ON entry (hash);
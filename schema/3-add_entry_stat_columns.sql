@@ -0,0 +1,7 @@
+ALTER TABLE entry ADD COLUMN size INTEGER;
+ALTER TABLE entry ADD COLUMN mode INTEGER;
+ALTER TABLE entry ADD COLUMN uid INTEGER;
+ALTER TABLE entry ADD COLUMN gid INTEGER;
+ALTER TABLE entry ADD COLUMN inode INTEGER;
+ALTER TABLE entry ADD COLUMN device INTEGER;
+ALTER TABLE entry ADD COLUMN nlink INTEGER;
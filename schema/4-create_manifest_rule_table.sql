@@ -0,0 +1,8 @@
+CREATE TABLE manifest_rule (
+	id INTEGER PRIMARY KEY,
+	manifest_id INTEGER NOT NULL,
+	kind TEXT NOT NULL,
+	pattern TEXT NOT NULL,
+	FOREIGN KEY (manifest_id) REFERENCES manifest (id)
+);
+CREATE INDEX manifest_rule_manifest_id ON manifest_rule (manifest_id);
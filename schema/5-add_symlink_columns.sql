@@ -0,0 +1,3 @@
+ALTER TABLE manifest ADD COLUMN symlink_policy TEXT;
+ALTER TABLE entry ADD COLUMN kind TEXT NOT NULL DEFAULT 'file';
+ALTER TABLE entry ADD COLUMN link_target TEXT;
@@ -0,0 +1 @@
+ALTER TABLE entry ADD COLUMN changed TEXT;
@@ -0,0 +1 @@
+ALTER TABLE manifest ADD COLUMN state TEXT NOT NULL DEFAULT 'complete';
@@ -0,0 +1,9 @@
+CREATE TABLE scan_error (
+	id INTEGER PRIMARY KEY,
+	manifest_id INTEGER NOT NULL,
+	file_path TEXT NOT NULL,
+	kind TEXT NOT NULL,
+	errno INTEGER,
+	FOREIGN KEY (manifest_id) REFERENCES manifest (id)
+);
+CREATE INDEX scan_error_manifest_id_file_path ON scan_error (manifest_id, file_path);
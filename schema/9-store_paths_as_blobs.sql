@@ -0,0 +1,3 @@
+UPDATE manifest SET directory_path = CAST(directory_path AS BLOB);
+UPDATE entry SET file_path = CAST(file_path AS BLOB), link_target = CAST(link_target AS BLOB);
+UPDATE scan_error SET file_path = CAST(file_path AS BLOB);
@@ -0,0 +1,222 @@
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::Error;
+use crate::filemetadata::{FileMetadata, Kind};
+use crate::hash::Algorithm;
+use crate::manifest::Manifest;
+
+/// Write the `algorithm` digest of every file of a manifest, with its path
+/// relative to the root. Symbolic links are left out, as their digest is of
+/// the target path rather than of content.
+pub fn export<W: Write>(
+    writer: &mut W,
+    manifest: &Manifest,
+    files: &[FileMetadata],
+    algorithm: Algorithm,
+) -> Result<(), Error> {
+    if !manifest.algorithms().contains(&algorithm) {
+        return Err(Error::NoDigest(
+            manifest.id().0,
+            algorithm.as_str().to_string(),
+        ));
+    }
+    let primary = manifest.algorithms().first() == Some(&algorithm);
+    for file in files.iter().filter(|file| file.kind() == &Kind::File) {
+        let hash = if primary {
+            Some(file.hash())
+        } else {
+            file.digests()
+                .iter()
+                .find(|(digest_algorithm, _)| *digest_algorithm == algorithm)
+                .map(|(_, digest)| digest.as_str())
+        };
+        if let Some(hash) = hash {
+            let path = file
+                .path()
+                .strip_prefix(manifest.file_path())
+                .unwrap_or_else(|_| file.path());
+            write_line(writer, hash, path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write one line in the format of GNU `sha256sum` and `b3sum`. A name
+/// holding a backslash or a line break is escaped, and the line then starts
+/// with a backslash, so the file can still be checked by those tools.
+pub fn write_line<W: Write>(writer: &mut W, hash: &str, path: &Path) -> io::Result<()> {
+    let name = path.as_os_str().as_bytes();
+    let escape = name
+        .iter()
+        .any(|byte| matches!(byte, b'\\' | b'\n' | b'\r'));
+    if escape {
+        writer.write_all(b"\\")?;
+    }
+    write!(writer, "{}  ", hash)?;
+    if escape {
+        for byte in name {
+            match byte {
+                b'\\' => writer.write_all(b"\\\\")?,
+                b'\n' => writer.write_all(b"\\n")?,
+                b'\r' => writer.write_all(b"\\r")?,
+                byte => writer.write_all(&[*byte])?,
+            }
+        }
+    } else {
+        writer.write_all(name)?;
+    }
+    writer.write_all(b"\n")
+}
+
+/// Read the lines of a checksum file into paths, as written, and lowercase
+/// hex digests of `digest_length` characters. Both text (`hash  name`) and
+/// binary (`hash *name`) lines are accepted. A name must stay below the
+/// root, so absolute names and names with `..` are refused.
+pub fn parse(content: &[u8], digest_length: usize) -> Result<Vec<(PathBuf, String)>, Error> {
+    let mut entries = Vec::new();
+    for (number, line) in content.split(|byte| *byte == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || Error::InvalidChecksumLine(number + 1);
+        let (escaped, line) = match line.strip_prefix(b"\\") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        if line.len() < digest_length + 2 {
+            return Err(invalid());
+        }
+        let (hash, rest) = line.split_at(digest_length);
+        if !hash.iter().all(u8::is_ascii_hexdigit) {
+            return Err(invalid());
+        }
+        let name = match rest {
+            [b' ', b' ', name @ ..] | [b' ', b'*', name @ ..] if !name.is_empty() => name,
+            _ => return Err(invalid()),
+        };
+        let name = if escaped {
+            unescape(name).ok_or_else(invalid)?
+        } else {
+            name.to_vec()
+        };
+        let path = PathBuf::from(OsStr::from_bytes(&name));
+        let below_root = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !below_root {
+            return Err(invalid());
+        }
+        let hash = String::from_utf8_lossy(hash).to_ascii_lowercase();
+        entries.push((path, hash));
+    }
+    Ok(entries)
+}
+
+fn unescape(name: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte != b'\\' {
+            unescaped.push(*byte);
+            continue;
+        }
+        match bytes.next()? {
+            b'\\' => unescaped.push(b'\\'),
+            b'n' => unescaped.push(b'\n'),
+            b'r' => unescaped.push(b'\r'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+/// Resolve a path from a checksum file against `root`, dropping the `./`
+/// that `sha256sum ./file` leaves in front of names.
+pub fn resolve(root: &Path, path: &Path) -> PathBuf {
+    let relative: PathBuf = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    root.join(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn lines_round_trip() {
+        let names: [&[u8]; 4] = [
+            b"plain.txt",
+            b"dir/with  two spaces",
+            b"back\\slash",
+            b"line\nbreak\rand \xff",
+        ];
+        for name in names.iter() {
+            let path = Path::new(OsStr::from_bytes(name));
+            let mut line = Vec::new();
+            write_line(&mut line, SHA256, path).unwrap();
+            let parsed = parse(&line, SHA256.len()).unwrap();
+            assert_eq!(parsed, vec![(path.to_path_buf(), SHA256.to_string())]);
+        }
+    }
+
+    #[test]
+    fn escaped_lines_start_with_a_backslash() {
+        let mut line = Vec::new();
+        write_line(&mut line, SHA256, Path::new("a\\b\nc")).unwrap();
+        assert_eq!(line, format!("\\{}  a\\\\b\\nc\n", SHA256).into_bytes());
+    }
+
+    #[test]
+    fn text_and_binary_lines_are_read() {
+        let content = format!(
+            "{}  ./text\r\n\n{} *binary\n",
+            SHA256.to_ascii_uppercase(),
+            SHA256
+        );
+        let parsed = parse(content.as_bytes(), SHA256.len()).unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                (PathBuf::from("./text"), SHA256.to_string()),
+                (PathBuf::from("binary"), SHA256.to_string()),
+            ]
+        );
+        assert_eq!(
+            resolve(Path::new("/root"), &parsed[0].0),
+            Path::new("/root/text")
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_refused() {
+        let lines = [
+            format!("{} name", SHA256),
+            format!("{}  ", SHA256),
+            format!("{}  name", &SHA256[1..]),
+            format!("{}x  name", &SHA256[1..]),
+            format!("\\{}  bad\\escape", SHA256),
+            format!("{}  /etc/passwd", SHA256),
+            format!("{}  ../outside", SHA256),
+            format!("{}  inside/../../outside", SHA256),
+        ];
+        for line in lines.iter() {
+            let content = format!("{}  fine\n{}\n", SHA256, line);
+            assert!(
+                matches!(
+                    parse(content.as_bytes(), SHA256.len()),
+                    Err(Error::InvalidChecksumLine(2))
+                ),
+                "{}",
+                line
+            );
+        }
+    }
+}
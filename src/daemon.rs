@@ -0,0 +1,313 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+
+use crate::error::Error;
+use crate::get_database;
+use crate::manifest::State;
+use crate::operation::{Outcome, Settings};
+use crate::output::Record;
+use crate::rules::Rules;
+use crate::schedule::Schedule;
+
+const SOCKET_FILE: &str = "scanner.sock";
+
+/// Where the daemon serves its status unless told otherwise: next to the
+/// database, so both sides find it through the same settings.
+pub fn socket_path(database_path: &Path) -> PathBuf {
+    database_path.with_file_name(SOCKET_FILE)
+}
+
+/// A root the daemon scans on a schedule, keeping its newest manifests.
+#[derive(Debug, Clone)]
+pub struct Job {
+    schedule: Schedule,
+    // The schedule as written, for the status
+    schedule_text: String,
+    keep: usize,
+    root: PathBuf,
+}
+
+/// Read a configuration of one job per line: a schedule, how many of the
+/// root's manifests to keep, and the root, as in
+///
+/// ```text
+/// @every 6h   10  /srv/data
+/// 0 3 * * *   30  /etc
+/// ```
+///
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_config(content: &str) -> Result<Vec<Job>, Error> {
+    let mut jobs = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || Error::InvalidDaemonConfig(number + 1);
+        let fields = match line.split_whitespace().next() {
+            Some("@every") => 2,
+            Some(shorthand) if shorthand.starts_with('@') => 1,
+            _ => 5,
+        };
+        let (words, rest) = split_words(line, fields + 1).ok_or_else(invalid)?;
+        let schedule_text = words[..fields].join(" ");
+        let schedule = schedule_text.parse::<Schedule>()?;
+        let keep = words[fields]
+            .parse::<usize>()
+            .ok()
+            .filter(|keep| *keep > 0)
+            .ok_or_else(invalid)?;
+        let root = PathBuf::from(rest);
+        if !root.is_absolute() {
+            return Err(invalid());
+        }
+        jobs.push(Job {
+            schedule,
+            schedule_text,
+            keep,
+            root,
+        });
+    }
+    Ok(jobs)
+}
+
+/// The first `count` words of a line and the rest of it, which may hold
+/// spaces.
+fn split_words(line: &str, count: usize) -> Option<(Vec<&str>, &str)> {
+    let mut words = Vec::with_capacity(count);
+    let mut rest = line;
+    for _ in 0..count {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        words.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    match rest.trim() {
+        "" => None,
+        rest => Some((words, rest)),
+    }
+}
+
+/// What the daemon reports about one of its jobs.
+#[derive(Debug, Clone)]
+pub struct Status {
+    root: PathBuf,
+    schedule: String,
+    keep: usize,
+    running: bool,
+    last_run: Option<i64>,
+    // The outcome of the last run, or the error it stopped on
+    result: Option<String>,
+    next_run: i64,
+}
+
+impl Status {
+    fn new(job: &Job, next_run: OffsetDateTime) -> Self {
+        Status {
+            root: job.root.clone(),
+            schedule: job.schedule_text.clone(),
+            keep: job.keep,
+            running: false,
+            last_run: None,
+            result: None,
+            next_run: next_run.unix_timestamp(),
+        }
+    }
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Status {
+            root: PathBuf::from(value.get("root")?.as_str()?),
+            schedule: value.get("schedule")?.as_str()?.to_string(),
+            keep: value.get("keep")?.as_u64()? as usize,
+            running: value.get("running")?.as_bool()?,
+            last_run: value.get("last_run")?.as_i64(),
+            result: value.get("result")?.as_str().map(str::to_string),
+            next_run: value.get("next_run")?.as_i64()?,
+        })
+    }
+}
+
+impl Record for Status {
+    fn to_json(&self) -> Value {
+        json!({
+            "root": self.root.to_string_lossy(),
+            "schedule": self.schedule,
+            "keep": self.keep,
+            "running": self.running,
+            "last_run": self.last_run,
+            "result": self.result,
+            "next_run": self.next_run,
+        })
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| String::from("-"));
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.schedule,
+            self.keep,
+            if self.running { "running" } else { "idle" },
+            optional(self.last_run.map(|time| time.to_string())),
+            optional(self.result.clone()),
+            self.next_run,
+            self.root.display()
+        )
+    }
+}
+
+/// Run the jobs one at a time as they fall due, forever, serving their
+/// status on `socket`. As only one job runs at a time, runs never overlap;
+/// a job that falls due during another's run waits for it to end.
+pub fn run(
+    database_path: &Path,
+    settings: Settings,
+    jobs: Vec<Job>,
+    socket: &Path,
+) -> Result<(), Error> {
+    let listener = bind(socket)?;
+    let now = OffsetDateTime::now_utc();
+    let statuses: Vec<Status> = jobs
+        .iter()
+        .map(|job| Status::new(job, job.schedule.next(None, now)))
+        .collect();
+    let statuses = Arc::new(Mutex::new(statuses));
+    let served = Arc::clone(&statuses);
+    thread::spawn(move || serve(listener, &served));
+    loop {
+        let due = lock(&statuses)
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, status)| status.next_run)
+            .map(|(index, status)| (index, status.next_run));
+        let (index, next_run) = match due {
+            Some(due) => due,
+            // Nothing to schedule; keep serving the empty status
+            None => loop {
+                thread::park();
+            },
+        };
+        let wait = next_run - OffsetDateTime::now_utc().unix_timestamp();
+        if wait > 0 {
+            thread::sleep(std::time::Duration::from_secs(wait as u64));
+        }
+        let started = OffsetDateTime::now_utc();
+        if let Some(status) = lock(&statuses).get_mut(index) {
+            status.running = true;
+            status.last_run = Some(started.unix_timestamp());
+        }
+        let job = &jobs[index];
+        let result = match run_job(database_path, settings, job) {
+            Ok(outcome) => outcome.as_str().to_string(),
+            Err(e) => {
+                eprintln!("Error: {}: {}", job.root.display(), e);
+                e.to_string()
+            }
+        };
+        let next_run = job.schedule.next(Some(started), OffsetDateTime::now_utc());
+        if let Some(status) = lock(&statuses).get_mut(index) {
+            status.running = false;
+            status.result = Some(result);
+            status.next_run = next_run.unix_timestamp();
+        }
+    }
+}
+
+/// The statuses stay readable even if the thread serving them panicked.
+fn lock(statuses: &Mutex<Vec<Status>>) -> MutexGuard<'_, Vec<Status>> {
+    statuses.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Scan a job's root against its newest complete manifest, or index it if
+/// there is none, without printing the results. Then delete all but the
+/// newest manifests the daemon took of the root. Only complete manifests the
+/// daemon took count towards `keep`, so imported and hand-made manifests
+/// are left alone, and as `keep` is at least one, the run just finished is
+/// never deleted.
+fn run_job(database_path: &Path, settings: Settings, job: &Job) -> Result<Outcome, Error> {
+    let mut database = get_database(database_path)?;
+    let latest = database
+        .select_manifests()?
+        .into_iter()
+        .rev()
+        .find(|manifest| manifest.file_path() == job.root && manifest.state() == State::Complete);
+    let (manifest_id, outcome) = match latest {
+        Some(manifest) => {
+            let (manifest_id, differences, failures) =
+                crate::rescan(&mut database, settings, &manifest, false)?;
+            let outcome = Outcome::from_differences(&differences, failures.len());
+            (manifest_id, outcome)
+        }
+        None => {
+            let (manifest_id, failures) = crate::index(
+                &mut database,
+                settings,
+                job.root.clone(),
+                Rules::default(),
+                Vec::new(),
+            )?;
+            (manifest_id, Outcome::new(false, failures.len()))
+        }
+    };
+    database.update_manifest_scheduled(&manifest_id)?;
+    let scheduled = database.select_scheduled_manifests(&job.root)?;
+    let expired = scheduled.len().saturating_sub(job.keep);
+    for manifest_id in &scheduled[..expired] {
+        database.delete_manifest(manifest_id)?;
+    }
+    Ok(outcome)
+}
+
+/// Listen on `socket`, replacing a socket file left behind by a daemon that
+/// is gone, but refusing to start beside one that still answers.
+fn bind(socket: &Path) -> Result<UnixListener, Error> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(Error::DaemonRunning(socket.to_path_buf()));
+        }
+        fs::remove_file(socket)?;
+    }
+    Ok(UnixListener::bind(socket)?)
+}
+
+/// Answer every connection with the status of all jobs, as JSON.
+fn serve(listener: UnixListener, statuses: &Mutex<Vec<Status>>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let records = lock(statuses).iter().map(Record::to_json).collect();
+        // A client that hangs up early is not the daemon's problem
+        let _ = writeln!(stream, "{}", Value::Array(records));
+    }
+}
+
+/// Ask the daemon listening on `socket` for the status of its jobs.
+pub fn query(socket: &Path) -> Result<Vec<Status>, Error> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+            Error::NoDaemon(socket.to_path_buf())
+        }
+        _ => Error::IO(e),
+    })?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let invalid = || Error::InvalidDaemonReply(socket.to_path_buf());
+    let records: Value = serde_json::from_str(&reply).map_err(|_| invalid())?;
+    records
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|record| Status::from_json(record).ok_or_else(invalid))
+        .collect()
+}
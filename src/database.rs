@@ -1,41 +1,355 @@
-use std::path::Path;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 use crate::difference;
 use crate::error::Error;
-use crate::filemetadata::FileMetadata;
-use crate::manifest::{Id, Manifest, Timestamp};
-use rusqlite::{params, Connection};
+use crate::filemetadata::{FileMetadata, Kind, Stat};
+use crate::hash::Algorithm;
+use crate::manifest::{Id, Manifest, State, Timestamp};
+use crate::rules::{Rules, SymlinkPolicy};
+use crate::scanerror::ScanError;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Row, Rows, Transaction};
 
-pub struct Database {
-    connection: Connection,
+/// The schema that replaced the per-manifest tables with `entry`.
+const ENTRY_TABLE_VERSION: u16 = 2;
+
+/// The schema from which entry paths are stored relative to the root.
+const RELATIVE_PATHS_VERSION: u16 = 10;
+
+/// How many entries are written per transaction while a scan streams in.
+pub(crate) const INSERT_BATCH: usize = 10_000;
+
+const RULE_INCLUDE: &str = "include";
+const RULE_EXCLUDE: &str = "exclude";
+
+/// The entry columns `entry_from_row` reads, in order.
+const ENTRY_COLUMNS: [&str; 18] = [
+    "file_path",
+    "hash",
+    "created",
+    "modified",
+    "accessed",
+    "size",
+    "mode",
+    "uid",
+    "gid",
+    "inode",
+    "device",
+    "nlink",
+    "kind",
+    "link_target",
+    "changed",
+    "id",
+    "manifest_id",
+    "digests",
+];
+
+fn entry_columns(alias: &str) -> String {
+    ENTRY_COLUMNS
+        .iter()
+        .map(|column| format!("{}.{}", alias, column))
+        .collect::<Vec<String>>()
+        .join(", ")
 }
 
-impl Database {
-    pub fn create<I, T>(p: &Path, tables: I) -> Result<Self, Error>
-    where
-        I: Iterator<Item = T>,
-        T: AsRef<str>,
-    {
-        let mut connection = Connection::open(p)?;
+/// Paths are stored as their raw bytes, so names that are not UTF-8 survive
+/// the round trip. Text, as written before, reads the same way.
+struct PathColumn(PathBuf);
 
-        let transaction = connection.transaction()?;
-        for table in tables {
-            transaction.execute(table.as_ref(), params![])?;
+impl FromSql for PathColumn {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => {
+                Ok(PathColumn(PathBuf::from(OsStr::from_bytes(bytes))))
+            }
+            _ => Err(FromSqlError::InvalidType),
         }
-        transaction.commit()?;
+    }
+}
+
+/// A manifest's hash algorithms, stored as a comma separated list.
+struct AlgorithmsColumn(Vec<Algorithm>);
+
+impl FromSql for AlgorithmsColumn {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Algorithm::parse_list(value.as_str()?)
+            .map(AlgorithmsColumn)
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
 
-        Ok(Database { connection })
+/// An entry's further digests, stored as `algorithm=digest` pairs separated
+/// by commas. NULL when the manifest has a single algorithm.
+struct DigestsColumn(Vec<(Algorithm, String)>);
+
+impl FromSql for DigestsColumn {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let mut digests = Vec::new();
+        for pair in value.as_str()?.split(',') {
+            let (algorithm, digest) = pair.split_once('=').ok_or(FromSqlError::InvalidType)?;
+            let algorithm = algorithm
+                .parse()
+                .map_err(|e: Error| FromSqlError::Other(Box::new(e)))?;
+            digests.push((algorithm, digest.to_string()));
+        }
+        Ok(DigestsColumn(digests))
+    }
+}
+
+fn digests_to_sql(digests: &[(Algorithm, String)]) -> Option<String> {
+    if digests.is_empty() {
+        return None;
     }
-    pub fn open(p: &Path) -> Result<Self, Error> {
+    let pairs: Vec<String> = digests
+        .iter()
+        .map(|(algorithm, digest)| format!("{}={}", algorithm.as_str(), digest))
+        .collect();
+    Some(pairs.join(","))
+}
+
+fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+/// Entry paths are stored relative to their manifest's root, so manifests
+/// of the same tree under different roots can be matched.
+fn relative_to<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+/// The inverse of `relative_to`; the root itself is stored as an empty path,
+/// which `Path::join` would turn into the root with a trailing separator.
+fn absolute(root: &Path, relative: PathBuf) -> PathBuf {
+    if relative.as_os_str().is_empty() {
+        root.to_path_buf()
+    } else {
+        root.join(relative)
+    }
+}
+
+/// SQL that holds when the scan error `error` hid the entry `entry`: the
+/// same path, or a path below a directory that could not be read. An error
+/// on the root itself has an empty path and hides everything.
+fn covered_by(error: &str, entry: &str) -> String {
+    format!(
+        "({e}.file_path = {n}.file_path OR length({e}.file_path) = 0 OR (substr({n}.file_path, 1, length({e}.file_path)) = {e}.file_path AND substr({n}.file_path, length({e}.file_path) + 1, 1) = X'2F'))",
+        e = error,
+        n = entry,
+    )
+}
+
+/// Read an entry from `row`, joining its path back onto `root`. Stored
+/// values that cannot be read become a `CorruptEntry` naming the manifest
+/// and row, so callers can skip them.
+fn entry_from_row(row: &Row, offset: usize, root: &Path) -> Result<FileMetadata, Error> {
+    let id: i64 = row.get(offset + 15)?;
+    let manifest_id: i64 = row.get(offset + 16)?;
+    read_entry(row, offset, root).map_err(|e| Error::CorruptEntry(manifest_id, id, Box::new(e)))
+}
+
+fn read_entry(row: &Row, offset: usize, root: &Path) -> Result<FileMetadata, Error> {
+    let stat = match row.get::<_, Option<i64>>(offset + 5)? {
+        Some(size) => Some(Stat::from_database(
+            size,
+            row.get(offset + 6)?,
+            row.get(offset + 7)?,
+            row.get(offset + 8)?,
+            row.get(offset + 9)?,
+            row.get(offset + 10)?,
+            row.get(offset + 11)?,
+            row.get(offset + 14)?,
+        )),
+        None => None,
+    };
+    let kind = match row.get::<_, Option<PathColumn>>(offset + 13)? {
+        Some(target) => Kind::Symlink(target.0),
+        None => Kind::File,
+    };
+    let digests = match row.get::<_, Option<DigestsColumn>>(offset + 17)? {
+        Some(digests) => digests.0,
+        None => Vec::new(),
+    };
+    Ok(FileMetadata::from_database(
+        absolute(root, row.get::<_, PathColumn>(offset)?.0),
+        row.get(offset + 1)?,
+        row.get(offset + 2)?,
+        row.get(offset + 3)?,
+        row.get(offset + 4)?,
+        stat,
+        kind,
+    )?
+    .with_digests(digests))
+}
+
+/// Read every row with `read`, reporting and skipping corrupt entries so
+/// that one bad row does not stop a whole listing or comparison.
+fn collect_rows<T, F>(mut rows: Rows, read: F) -> Result<Vec<T>, Error>
+where
+    F: Fn(&Row) -> Result<T, Error>,
+{
+    let mut results = Vec::new();
+    while let Some(row) = rows.next()? {
+        match read(row) {
+            Ok(result) => results.push(result),
+            Err(e @ Error::CorruptEntry(..)) => eprintln!("Skipping: {}", e),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(results)
+}
+
+pub struct Database {
+    connection: Connection,
+}
+
+impl Database {
+    pub fn create(p: &Path, schemas: &[(u16, String)]) -> Result<Self, Error> {
+        let connection = Connection::open(p)?;
+        let mut database = Database { connection };
+        database.migrate(schemas)?;
+        Ok(database)
+    }
+    pub fn open(p: &Path, schemas: &[(u16, String)]) -> Result<Self, Error> {
         if !p.is_file() {
             return Err(Error::NoFile(p.to_path_buf()));
         }
         let connection = Connection::open(p)?;
-        Ok(Database { connection })
+        let mut database = Database { connection };
+        database.migrate(schemas)?;
+        Ok(database)
+    }
+    /// Apply every schema numbered above the database's `user_version`, in
+    /// order and inside a single transaction. A database written by a newer
+    /// schema than the ones provided is refused.
+    fn migrate(&mut self, schemas: &[(u16, String)]) -> Result<(), Error> {
+        let current = self.schema_version()?;
+        let latest = schemas
+            .iter()
+            .map(|(version, _)| *version)
+            .max()
+            .unwrap_or(0);
+        if current > latest {
+            return Err(Error::SchemaTooNew(current, latest));
+        }
+        let transaction = self.connection.transaction()?;
+        for (version, sql) in schemas.iter().filter(|(version, _)| *version > current) {
+            transaction.execute_batch(sql)?;
+            if *version == ENTRY_TABLE_VERSION {
+                Database::move_legacy_tables(&transaction)?;
+            }
+            if *version == RELATIVE_PATHS_VERSION {
+                Database::relativize_paths(&transaction)?;
+            }
+        }
+        transaction.pragma_update(None, "user_version", latest)?;
+        transaction.commit()?;
+        Ok(())
+    }
+    /// The schema version recorded in `user_version`. Databases from before
+    /// versioning have none, so work it out from the tables they contain.
+    fn schema_version(&self) -> Result<u16, Error> {
+        let version: u16 = self
+            .connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != 0 {
+            return Ok(version);
+        }
+        if self.table_exists("entry")? {
+            Ok(ENTRY_TABLE_VERSION)
+        } else if self.table_exists("manifest")? {
+            Ok(ENTRY_TABLE_VERSION - 1)
+        } else {
+            Ok(0)
+        }
+    }
+    /// Databases written before the entry table existed keep one table per
+    /// manifest, named after its timestamp. Move their rows into `entry`.
+    fn move_legacy_tables(transaction: &Transaction) -> Result<(), Error> {
+        let sql = r#"
+            SELECT manifest.id, sqlite_master.name
+            FROM manifest
+            INNER JOIN sqlite_master
+            ON sqlite_master.type = 'table'
+            AND sqlite_master.name = CAST(manifest.timestamp AS TEXT)
+        "#;
+        let mut legacy_tables = Vec::new();
+        {
+            let mut statement = transaction.prepare(sql)?;
+            let iterator = statement.query_map(params![], |row| {
+                Ok((Id(row.get(0)?), row.get::<_, String>(1)?))
+            })?;
+            for item in iterator {
+                legacy_tables.push(item?);
+            }
+        }
+        for (manifest_id, legacy_table) in legacy_tables {
+            let copy_sql = format!(
+                r#"
+                    INSERT INTO entry (manifest_id, file_path, hash, created, modified, accessed)
+                    SELECT ?1, file_path, hash, created, modified, accessed
+                    FROM '{}'
+                "#,
+                legacy_table
+            );
+            transaction.execute(&copy_sql, params![manifest_id.0])?;
+            transaction.execute(&format!("DROP TABLE '{}'", legacy_table), params![])?;
+        }
+        Ok(())
+    }
+    /// Rewrite the absolute entry and scan error paths of every manifest
+    /// relative to its root. Paths outside the root are left as they are.
+    fn relativize_paths(transaction: &Transaction) -> Result<(), Error> {
+        let mut roots = Vec::new();
+        {
+            let mut statement = transaction.prepare("SELECT id, directory_path FROM manifest")?;
+            let iterator = statement.query_map(params![], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, PathColumn>(1)?.0))
+            })?;
+            for item in iterator {
+                roots.push(item?);
+            }
+        }
+        for table in ["entry", "scan_error"].iter() {
+            let select_sql = format!("SELECT id, file_path FROM {} WHERE manifest_id = ?1", table);
+            let update_sql = format!("UPDATE {} SET file_path = ?2 WHERE id = ?1", table);
+            for (manifest_id, root) in roots.iter() {
+                let mut paths = Vec::new();
+                {
+                    let mut statement = transaction.prepare(&select_sql)?;
+                    let iterator = statement.query_map(params![manifest_id], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, PathColumn>(1)?.0))
+                    })?;
+                    for item in iterator {
+                        paths.push(item?);
+                    }
+                }
+                let mut statement = transaction.prepare(&update_sql)?;
+                for (id, path) in paths {
+                    if let Ok(relative) = path.strip_prefix(root) {
+                        statement.execute(params![id, path_bytes(relative)])?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn table_exists(&self, name: &str) -> Result<bool, Error> {
+        let sql = r#"
+            SELECT name
+            FROM sqlite_master
+            WHERE type = 'table' AND name = ?1
+        "#;
+        let table: Option<String> = self
+            .connection
+            .query_row(sql, params![name], |row| row.get(0))
+            .optional()?;
+        Ok(table.is_some())
     }
     pub fn select_manifests(&self) -> Result<Vec<Manifest>, Error> {
         let sql = r#"
-            SELECT id, timestamp, directory_path
+            SELECT id, timestamp, directory_path, state, hash_algorithms
             FROM manifest
             ORDER BY id ASC
         "#;
@@ -44,7 +358,9 @@ impl Database {
             Ok(Manifest::from_database(
                 Id(row.get(0)?),
                 Timestamp(row.get(1)?),
-                row.get(2)?,
+                row.get::<_, PathColumn>(2)?.0,
+                row.get(3)?,
+                row.get::<_, AlgorithmsColumn>(4)?.0,
             ))
         })?;
         let mut results = Vec::new();
@@ -55,7 +371,7 @@ impl Database {
     }
     pub fn select_manifest(&self, id: &Id) -> Result<Manifest, Error> {
         let sql = r#"
-            SELECT id, timestamp, directory_path
+            SELECT id, timestamp, directory_path, state, hash_algorithms
             FROM manifest
             WHERE id = ?1
         "#;
@@ -63,110 +379,365 @@ impl Database {
             Ok(Manifest::from_database(
                 Id(row.get(0)?),
                 Timestamp(row.get(1)?),
-                row.get(2)?,
+                row.get::<_, PathColumn>(2)?.0,
+                row.get(3)?,
+                row.get::<_, AlgorithmsColumn>(4)?.0,
             ))
         })?;
         Ok(record)
     }
-    pub fn create_manifest_table(
+    pub fn manifest_root(&self, manifest_id: &Id) -> Result<PathBuf, Error> {
+        let sql = r#"
+            SELECT directory_path
+            FROM manifest
+            WHERE id = ?1
+        "#;
+        let root = self
+            .connection
+            .query_row(sql, params![manifest_id.0], |row| {
+                row.get::<_, PathColumn>(0)
+            })?;
+        Ok(root.0)
+    }
+    pub fn create_manifest(
         &mut self,
         timestamp: &Timestamp,
         path: &Path,
-    ) -> Result<(), Error> {
+        rules: &Rules,
+        algorithms: &[Algorithm],
+    ) -> Result<Id, Error> {
         let sql = r#"
-            INSERT INTO manifest (timestamp, directory_path)
-            VALUES (?1, ?2)
+            INSERT INTO manifest (timestamp, directory_path, symlink_policy, state, hash_algorithms)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+        "#;
+        let rule_sql = r#"
+            INSERT INTO manifest_rule (manifest_id, kind, pattern)
+            VALUES (?1, ?2, ?3)
         "#;
-        let create_table_sql = format!(
-            r#"
-                CREATE TABLE '{}' (
-                    id INTEGER PRIMARY KEY,
-                    file_path TEXT NOT NULL,
-                    hash TEXT NOT NULL,
-                    created TEXT,
-                    modified TEXT,
-                    accessed TEXT,
-                    manifest_id INTEGER NOT NULL,
-                    FOREIGN KEY (manifest_id) REFERENCES manifest (id)
-                )
-            "#,
-            timestamp.0
-        );
         let transaction = self.connection.transaction()?;
-        let path = path.to_str().unwrap_or("default");
-        transaction.execute(sql, params![timestamp.0, path])?;
-        transaction.execute(&create_table_sql, params![])?;
+        let path = path_bytes(path);
+        transaction.execute(
+            sql,
+            params![
+                timestamp.0,
+                path,
+                rules.symlinks().as_str(),
+                State::InProgress,
+                Algorithm::format_list(algorithms)
+            ],
+        )?;
+        let manifest_id = Id(transaction.last_insert_rowid());
+        for pattern in rules.includes() {
+            transaction.execute(rule_sql, params![manifest_id.0, RULE_INCLUDE, pattern])?;
+        }
+        for pattern in rules.excludes() {
+            transaction.execute(rule_sql, params![manifest_id.0, RULE_EXCLUDE, pattern])?;
+        }
         transaction.commit()?;
+        Ok(manifest_id)
+    }
+    pub fn update_manifest_state(&mut self, manifest_id: &Id, state: State) -> Result<(), Error> {
+        let sql = r#"
+            UPDATE manifest
+            SET state = ?2
+            WHERE id = ?1
+        "#;
+        self.connection
+            .execute(sql, params![manifest_id.0, state])?;
+        Ok(())
+    }
+    /// Mark a manifest as taken by the daemon, so its retention applies.
+    pub fn update_manifest_scheduled(&mut self, manifest_id: &Id) -> Result<(), Error> {
+        let sql = r#"
+            UPDATE manifest
+            SET scheduled = 1
+            WHERE id = ?1
+        "#;
+        self.connection.execute(sql, params![manifest_id.0])?;
         Ok(())
     }
-    pub fn delete_manifest_drop_table(&mut self, manifest_id: &Id) -> Result<(), Error> {
+    /// The complete manifests of `root` that the daemon took, oldest first.
+    pub fn select_scheduled_manifests(&self, root: &Path) -> Result<Vec<Id>, Error> {
+        let sql = r#"
+            SELECT id
+            FROM manifest
+            WHERE directory_path = ?1 AND scheduled = 1 AND state = ?2
+            ORDER BY id ASC
+        "#;
+        let mut statement = self.connection.prepare(sql)?;
+        let iterator = statement.query_map(params![path_bytes(root), State::Complete], |row| {
+            Ok(Id(row.get(0)?))
+        })?;
+        let mut results = Vec::new();
+        for result in iterator {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+    pub fn select_manifest_rules(&self, manifest_id: &Id) -> Result<Rules, Error> {
+        let policy_sql = r#"
+            SELECT symlink_policy
+            FROM manifest
+            WHERE id = ?1
+        "#;
+        let sql = r#"
+            SELECT kind, pattern
+            FROM manifest_rule
+            WHERE manifest_id = ?1
+            ORDER BY id ASC
+        "#;
+        let mut statement = self.connection.prepare(sql)?;
+        let iterator = statement.query_map(params![manifest_id.0], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut includes = Vec::new();
+        let mut excludes = Vec::new();
+        for result in iterator {
+            let (kind, pattern) = result?;
+            if kind == RULE_INCLUDE {
+                includes.push(pattern);
+            } else {
+                excludes.push(pattern);
+            }
+        }
+        // Manifests from before the policy existed were scanned following links
+        let symlinks =
+            match self
+                .connection
+                .query_row(policy_sql, params![manifest_id.0], |row| {
+                    row.get::<_, Option<String>>(0)
+                })? {
+                Some(policy) => policy.parse()?,
+                None => SymlinkPolicy::Follow,
+            };
+        Ok(Rules::new(includes, excludes, symlinks))
+    }
+    pub fn delete_manifest(&mut self, manifest_id: &Id) -> Result<(), Error> {
         let sql = r#"
             DELETE FROM manifest
             WHERE id = ?1
         "#;
+        let delete_entries_sql = r#"
+            DELETE FROM entry
+            WHERE manifest_id = ?1
+        "#;
+        let delete_rules_sql = r#"
+            DELETE FROM manifest_rule
+            WHERE manifest_id = ?1
+        "#;
+        let delete_errors_sql = r#"
+            DELETE FROM scan_error
+            WHERE manifest_id = ?1
+        "#;
+        let delete_changes_sql = r#"
+            DELETE FROM watch_change
+            WHERE manifest_id = ?1
+        "#;
         let manifest_record = self.select_manifest(manifest_id)?;
-        let drop_table_sql = format!(
-            r#"
-                DROP TABLE '{}'
-            "#,
-            manifest_record.timestamp().0,
-        );
         let transaction = self.connection.transaction()?;
+        transaction.execute(delete_entries_sql, params![manifest_record.id().0])?;
+        transaction.execute(delete_rules_sql, params![manifest_record.id().0])?;
+        transaction.execute(delete_errors_sql, params![manifest_record.id().0])?;
+        transaction.execute(delete_changes_sql, params![manifest_record.id().0])?;
         transaction.execute(sql, params![manifest_record.id().0])?;
-        transaction.execute(&drop_table_sql, params![])?;
         transaction.commit()?;
         Ok(())
     }
+    /// Entries are committed in batches as the iterator yields them, so a
+    /// streaming scan is written while it is still running.
     pub fn insert_file_paths_and_hashes<I>(
         &mut self,
-        timestamp: &Timestamp,
+        manifest_id: &Id,
         iterator: I,
     ) -> Result<(), Error>
     where
         I: Iterator<Item = FileMetadata>,
     {
         let sql = r#"
-            SELECT id
-            FROM manifest
-            WHERE timestamp = ?1
+            INSERT INTO entry (
+                manifest_id, file_path, hash, created, modified, accessed,
+                size, mode, uid, gid, inode, device, nlink, kind, link_target, changed,
+                digests
+            )
+            VALUES (
+                ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17
+            )
         "#;
+        let root = self.manifest_root(manifest_id)?;
+        let mut iterator = iterator.peekable();
+        while iterator.peek().is_some() {
+            let transaction = self.connection.transaction()?;
+            {
+                let mut statement = transaction.prepare_cached(sql)?;
+                for file in iterator.by_ref().take(INSERT_BATCH) {
+                    let stat = file.stat();
+                    statement.execute(params![
+                        manifest_id.0,
+                        path_bytes(relative_to(&root, file.path())),
+                        file.hash(),
+                        file.created(),
+                        file.modified(),
+                        file.accessed(),
+                        stat.map(|stat| stat.size() as i64),
+                        stat.map(|stat| stat.mode()),
+                        stat.map(|stat| stat.uid()),
+                        stat.map(|stat| stat.gid()),
+                        stat.map(|stat| stat.inode() as i64),
+                        stat.map(|stat| stat.device() as i64),
+                        stat.map(|stat| stat.nlink() as i64),
+                        file.kind().as_str(),
+                        file.kind().link_target().map(path_bytes),
+                        stat.and_then(|stat| stat.changed()),
+                        digests_to_sql(file.digests()),
+                    ])?;
+                }
+            }
+            transaction.commit()?;
+        }
+        Ok(())
+    }
+    pub fn insert_scan_errors(
+        &mut self,
+        manifest_id: &Id,
+        errors: &[ScanError],
+    ) -> Result<(), Error> {
+        let sql = r#"
+            INSERT INTO scan_error (manifest_id, file_path, kind, errno)
+            VALUES (?1, ?2, ?3, ?4)
+        "#;
+        let root = self.manifest_root(manifest_id)?;
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(sql)?;
+            for error in errors {
+                statement.execute(params![
+                    manifest_id.0,
+                    path_bytes(relative_to(&root, error.path())),
+                    error.kind(),
+                    error.errno()
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+    /// Record differences seen while watching a manifest's root.
+    pub fn insert_watch_changes(
+        &mut self,
+        manifest_id: &Id,
+        timestamp: &Timestamp,
+        changes: &[difference::Type],
+    ) -> Result<(), Error> {
+        let sql = r#"
+            INSERT INTO watch_change (manifest_id, timestamp, type, file_path, hash)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+        "#;
+        let root = self.manifest_root(manifest_id)?;
         let transaction = self.connection.transaction()?;
-        let insert_sql = format!(
+        {
+            let mut statement = transaction.prepare_cached(sql)?;
+            for change in changes {
+                statement.execute(params![
+                    manifest_id.0,
+                    timestamp.0,
+                    change.kind(),
+                    path_bytes(relative_to(&root, change.path())),
+                    change.new_file().map(|file| file.hash())
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+    pub fn select_scan_errors(&self, manifest_id: &Id) -> Result<Vec<ScanError>, Error> {
+        let sql = r#"
+            SELECT file_path, kind, errno
+            FROM scan_error
+            WHERE manifest_id = ?1
+            ORDER BY file_path ASC
+        "#;
+        let root = self.manifest_root(manifest_id)?;
+        let mut statement = self.connection.prepare(sql)?;
+        let iterator = statement.query_map(params![manifest_id.0], |row| {
+            Ok(ScanError::from_database(
+                absolute(&root, row.get::<_, PathColumn>(0)?.0),
+                row.get(1)?,
+                row.get(2)?,
+            ))
+        })?;
+        let mut results = Vec::new();
+        for result in iterator {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+    pub fn select_entries(&self, manifest_id: &Id) -> Result<Vec<FileMetadata>, Error> {
+        let sql = format!(
             r#"
-                INSERT INTO '{}' (file_path, hash, created, modified, accessed, manifest_id)
-                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                SELECT {}
+                FROM entry AS e
+                WHERE e.manifest_id = ?1
+                ORDER BY e.file_path ASC
             "#,
-            timestamp.0,
+            entry_columns("e"),
         );
-        let manifest_id =
-            transaction.query_row(sql, params![timestamp.0], |row| Ok(Id(row.get(0)?)))?;
-        for file in iterator {
-            // Hack for now...probably should be done when scanning or use a u8 vec for path?
-            let converted = file.path().to_str().unwrap_or("default");
-            transaction.execute(
-                &insert_sql,
-                params![
-                    converted,
-                    file.hash(),
-                    file.created(),
-                    file.modified(),
-                    file.accessed(),
-                    manifest_id.0
-                ],
-            )?;
+        let root = self.manifest_root(manifest_id)?;
+        let mut statement = self.connection.prepare(&sql)?;
+        let rows = statement.query(params![manifest_id.0])?;
+        collect_rows(rows, |row| entry_from_row(row, 0, &root))
+    }
+    /// The paths of every entry a manifest recorded, joined onto its root.
+    pub fn select_entry_paths(&self, manifest_id: &Id) -> Result<Vec<PathBuf>, Error> {
+        let sql = r#"
+            SELECT file_path
+            FROM entry
+            WHERE manifest_id = ?1
+        "#;
+        let root = self.manifest_root(manifest_id)?;
+        let mut statement = self.connection.prepare(sql)?;
+        let iterator = statement.query_map(params![manifest_id.0], |row| {
+            Ok(absolute(&root, row.get::<_, PathColumn>(0)?.0))
+        })?;
+        let mut results = Vec::new();
+        for result in iterator {
+            results.push(result?);
         }
-        transaction.commit()?;
-        Ok(())
+        Ok(results)
     }
+    /// Entries are matched by their path relative to the root. Unless
+    /// `relative` is set, manifests with different roots share no paths, so
+    /// every entry is reported as removed or added. When they are matched
+    /// across roots, created times are not compared, as a copy never keeps
+    /// them.
     pub fn select_manifest_differences(
         &self,
-        new: &Timestamp,
-        old: &Timestamp,
+        new: &Id,
+        old: &Id,
+        relative: bool,
     ) -> Result<Option<Vec<difference::Type>>, Error> {
         let mut differences = Vec::new();
-        self.select_hash_differences(new, old, &mut differences)?;
-        self.select_removed_paths(new, old, &mut differences)?;
-        self.select_added_paths(new, old, &mut differences)?;
+        let same_root = self.manifest_root(new)? == self.manifest_root(old)?;
+        if !relative && !same_root {
+            // Every file would pair with itself, so no moves are looked for
+            differences.extend(
+                self.select_entries(old)?
+                    .into_iter()
+                    .map(difference::Type::Delete),
+            );
+            differences.extend(
+                self.select_entries(new)?
+                    .into_iter()
+                    .map(difference::Type::Add),
+            );
+        } else {
+            self.select_hash_differences(new, old, &mut differences)?;
+            self.select_metadata_differences(new, old, same_root, &mut differences)?;
+            self.select_unreadable_paths(new, old, &mut differences)?;
+            self.select_readable_paths(new, old, &mut differences)?;
+            self.select_removed_paths(new, old, &mut differences)?;
+            self.select_added_paths(new, old, &mut differences)?;
+            differences = difference::pair_moves(differences);
+        }
         if differences.is_empty() {
             Ok(None)
         } else {
@@ -175,137 +746,480 @@ impl Database {
     }
     fn select_hash_differences(
         &self,
-        new: &Timestamp,
-        old: &Timestamp,
+        new: &Id,
+        old: &Id,
+        differences: &mut Vec<difference::Type>,
+    ) -> Result<(), Error> {
+        self.select_changed_paths(
+            new,
+            old,
+            "n.hash != o.hash AND n.kind = 'file' AND o.kind = 'file'",
+            difference::Type::Hash,
+            differences,
+        )?;
+        self.select_changed_paths(
+            new,
+            old,
+            "(n.kind = 'symlink' OR o.kind = 'symlink') AND n.link_target IS NOT o.link_target",
+            difference::Type::Target,
+            differences,
+        )
+    }
+    /// Metadata changes are reported whether or not the content changed too.
+    /// Entries recorded without a stat compare as NULL and never match.
+    /// Created times are only compared between manifests of the same root.
+    fn select_metadata_differences(
+        &self,
+        new: &Id,
+        old: &Id,
+        same_root: bool,
+        differences: &mut Vec<difference::Type>,
+    ) -> Result<(), Error> {
+        self.select_changed_paths(
+            new,
+            old,
+            "n.mode != o.mode",
+            difference::Type::Permission,
+            differences,
+        )?;
+        self.select_changed_paths(
+            new,
+            old,
+            "(n.uid != o.uid OR n.gid != o.gid)",
+            difference::Type::Ownership,
+            differences,
+        )?;
+        self.select_changed_paths(
+            new,
+            old,
+            "n.size != o.size",
+            difference::Type::Size,
+            differences,
+        )?;
+        let timestamps = match same_root {
+            true => "(n.modified != o.modified OR n.created != o.created)",
+            false => "n.modified != o.modified",
+        };
+        self.select_changed_paths(
+            new,
+            old,
+            timestamps,
+            difference::Type::Timestamp,
+            differences,
+        )
+    }
+    /// Paths present in both manifests whose entries satisfy `condition`,
+    /// where `n` is the new entry and `o` the old one.
+    fn select_changed_paths(
+        &self,
+        new: &Id,
+        old: &Id,
+        condition: &str,
+        kind: fn(i64, FileMetadata, i64, FileMetadata) -> difference::Type,
         differences: &mut Vec<difference::Type>,
     ) -> Result<(), Error> {
         let sql = format!(
             r#"
-                SELECT
-                    n.file_path,
-                    n.hash,
-                    n.created,
-                    n.modified,
-                    n.accessed,
-                    o.file_path,
-                    o.hash,
-                    o.created,
-                    o.modified,
-                    o.accessed
-                FROM '{}' AS n
-                INNER JOIN '{}' AS o
-                ON n.file_path = o.file_path
-                WHERE n.hash != o.hash
+                SELECT {}, {}
+                FROM entry AS n
+                INNER JOIN entry AS o
+                ON o.manifest_id = ?2 AND n.file_path = o.file_path
+                WHERE n.manifest_id = ?1 AND {}
+                ORDER BY n.file_path ASC
             "#,
-            new.0, old.0
+            entry_columns("n"),
+            entry_columns("o"),
+            condition,
         );
+        let new_root = self.manifest_root(new)?;
+        let old_root = self.manifest_root(old)?;
         let mut statement = self.connection.prepare(&sql)?;
-        let iterator = statement.query_map(
-            params![],
-            |row| -> Result<(i64, FileMetadata, i64, FileMetadata), rusqlite::Error> {
-                let a = FileMetadata::from_database(
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                )
-                .unwrap();
-                let b = FileMetadata::from_database(
-                    row.get(5)?,
-                    row.get(6)?,
-                    row.get(7)?,
-                    row.get(8)?,
-                    row.get(9)?,
-                )
-                .unwrap();
-                Ok((old.0, a, new.0, b))
-            },
-        )?;
-        for item in iterator {
-            let item = item?;
-            let difference = difference::Type::Hash(item.0, item.1, item.2, item.3);
-            differences.push(difference);
+        let rows = statement.query(params![new.0, old.0])?;
+        let pairs = collect_rows(rows, |row| {
+            let a = entry_from_row(row, 0, &new_root)?;
+            let b = entry_from_row(row, ENTRY_COLUMNS.len(), &old_root)?;
+            Ok((a, b))
+        })?;
+        for (a, b) in pairs {
+            differences.push(kind(new.0, a, old.0, b));
         }
         Ok(())
     }
-    fn select_removed_paths(
+    /// Entries of the old manifest that the new one could not read.
+    fn select_unreadable_paths(
         &self,
-        new: &Timestamp,
-        old: &Timestamp,
+        new: &Id,
+        old: &Id,
         differences: &mut Vec<difference::Type>,
     ) -> Result<(), Error> {
+        for (error, file) in self.select_covered_paths(old, new)? {
+            differences.push(difference::Type::Unreadable(new.0, error, old.0, file));
+        }
+        Ok(())
+    }
+    /// Entries of the new manifest that the old one could not read.
+    fn select_readable_paths(
+        &self,
+        new: &Id,
+        old: &Id,
+        differences: &mut Vec<difference::Type>,
+    ) -> Result<(), Error> {
+        for (error, file) in self.select_covered_paths(new, old)? {
+            differences.push(difference::Type::Readable(new.0, file, old.0, error));
+        }
+        Ok(())
+    }
+    /// Entries of `present` with no entry in `failed`, paired with the scan
+    /// error of `failed` that hid them.
+    fn select_covered_paths(
+        &self,
+        present: &Id,
+        failed: &Id,
+    ) -> Result<Vec<(ScanError, FileMetadata)>, Error> {
         let sql = format!(
             r#"
-                SELECT
-                    n.file_path,
-                    n.hash,
-                    n.created,
-                    n.modified,
-                    n.accessed
-                FROM '{}' AS n
-                LEFT JOIN '{}' AS o
-                ON n.file_path = o.file_path
-                WHERE o.file_path IS NULL
+                SELECT s.file_path, s.kind, s.errno, {}
+                FROM entry AS p
+                INNER JOIN scan_error AS s
+                ON s.manifest_id = ?2 AND {}
+                WHERE p.manifest_id = ?1 AND NOT EXISTS (
+                    SELECT 1 FROM entry AS f
+                    WHERE f.manifest_id = ?2 AND f.file_path = p.file_path
+                )
+                ORDER BY p.file_path ASC
             "#,
-            old.0, new.0,
+            entry_columns("p"),
+            covered_by("s", "p"),
         );
+        let present_root = self.manifest_root(present)?;
+        let failed_root = self.manifest_root(failed)?;
         let mut statement = self.connection.prepare(&sql)?;
-        let iterator =
-            statement.query_map(params![], |row| -> Result<FileMetadata, rusqlite::Error> {
-                Ok(FileMetadata::from_database(
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                )
-                .unwrap())
-            })?;
-        for item in iterator {
-            let item = item?;
-            differences.push(difference::Type::Delete(item));
-        }
-        Ok(())
+        let rows = statement.query(params![present.0, failed.0])?;
+        collect_rows(rows, |row| {
+            let error = ScanError::from_database(
+                absolute(&failed_root, row.get::<_, PathColumn>(0)?.0),
+                row.get(1)?,
+                row.get(2)?,
+            );
+            Ok((error, entry_from_row(row, 3, &present_root)?))
+        })
+    }
+    fn select_removed_paths(
+        &self,
+        new: &Id,
+        old: &Id,
+        differences: &mut Vec<difference::Type>,
+    ) -> Result<(), Error> {
+        self.select_unmatched_paths(old, new, difference::Type::Delete, differences)
     }
     fn select_added_paths(
         &self,
-        new: &Timestamp,
-        old: &Timestamp,
+        new: &Id,
+        old: &Id,
+        differences: &mut Vec<difference::Type>,
+    ) -> Result<(), Error> {
+        self.select_unmatched_paths(new, old, difference::Type::Add, differences)
+    }
+    /// Entries of manifest `present` whose path has no entry in manifest
+    /// `absent` and was not hidden from it by a scan error.
+    fn select_unmatched_paths(
+        &self,
+        present: &Id,
+        absent: &Id,
+        kind: fn(FileMetadata) -> difference::Type,
         differences: &mut Vec<difference::Type>,
     ) -> Result<(), Error> {
         let sql = format!(
             r#"
-                SELECT
-                    n.file_path,
-                    n.hash,
-                    n.created,
-                    n.modified,
-                    n.accessed
-                FROM '{}' AS n
-                LEFT JOIN '{}' AS o
-                ON n.file_path = o.file_path
-                WHERE o.file_path IS NULL
+                SELECT {}
+                FROM entry AS p
+                LEFT JOIN entry AS a
+                ON a.manifest_id = ?2 AND p.file_path = a.file_path
+                WHERE p.manifest_id = ?1 AND a.id IS NULL AND NOT EXISTS (
+                    SELECT 1 FROM scan_error AS s
+                    WHERE s.manifest_id = ?2 AND {}
+                )
+                ORDER BY p.file_path ASC
             "#,
-            new.0, old.0
+            entry_columns("p"),
+            covered_by("s", "p"),
         );
+        let root = self.manifest_root(present)?;
         let mut statement = self.connection.prepare(&sql)?;
-        let iterator =
-            statement.query_map(params![], |row| -> Result<FileMetadata, rusqlite::Error> {
-                let new = FileMetadata::from_database(
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
+        let rows = statement.query(params![present.0, absent.0])?;
+        for file in collect_rows(rows, |row| entry_from_row(row, 0, &root))? {
+            differences.push(kind(file));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::embedded_schemas;
+    use std::io;
+
+    fn in_memory() -> Database {
+        Database {
+            connection: Connection::open_in_memory().unwrap(),
+        }
+    }
+
+    fn migrated() -> Database {
+        let mut database = in_memory();
+        database.migrate(&embedded_schemas().unwrap()).unwrap();
+        database
+    }
+
+    fn manifest(database: &mut Database, root: &str, files: Vec<FileMetadata>) -> Id {
+        let manifest_id = database
+            .create_manifest(
+                &Timestamp::now(),
+                Path::new(root),
+                &Rules::default(),
+                &[Algorithm::Sha256],
+            )
+            .unwrap();
+        database
+            .insert_file_paths_and_hashes(&manifest_id, files.into_iter())
+            .unwrap();
+        manifest_id
+    }
+
+    fn file(path: &str, hash: &str) -> FileMetadata {
+        FileMetadata::from_hash(PathBuf::from(path), hash.to_string()).unwrap()
+    }
+
+    /// Each difference's kind with the path of the file it is about, which
+    /// for a file that became unreadable is the file rather than the error.
+    fn summary(differences: Option<Vec<difference::Type>>) -> Vec<(&'static str, PathBuf)> {
+        let mut summary: Vec<(&'static str, PathBuf)> = differences
+            .unwrap_or_default()
+            .iter()
+            .map(|difference| {
+                let path = match difference {
+                    difference::Type::Unreadable(_, _, _, file) => file.path(),
+                    difference => difference.path(),
+                };
+                (difference.kind(), path.to_path_buf())
+            })
+            .collect();
+        summary.sort();
+        summary
+    }
+
+    fn expected(differences: &[(&'static str, &str)]) -> Vec<(&'static str, PathBuf)> {
+        differences
+            .iter()
+            .map(|(kind, path)| (*kind, PathBuf::from(path)))
+            .collect()
+    }
+
+    #[test]
+    fn legacy_tables_migrate_to_relative_entries() {
+        let schemas = embedded_schemas().unwrap();
+        let mut database = in_memory();
+        // As written before versioning: a manifest table, and one table of
+        // absolute paths per manifest, named after its timestamp
+        database.connection.execute_batch(&schemas[0].1).unwrap();
+        let modified = time::OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let manifests = [
+            (1000, "/data", vec!["/data/a", "/data/sub/b"]),
+            (2000, "/data/sub", vec!["/data/sub/b"]),
+        ];
+        for (timestamp, root, paths) in manifests.iter() {
+            database
+                .connection
+                .execute(
+                    "INSERT INTO manifest (timestamp, directory_path) VALUES (?1, ?2)",
+                    params![timestamp, root],
                 )
                 .unwrap();
-                Ok(new)
-            })?;
-        for item in iterator {
-            let item = item?;
-            differences.push(difference::Type::Add(item));
+            let manifest_id = database.connection.last_insert_rowid();
+            database
+                .connection
+                .execute_batch(&format!(
+                    r#"
+                        CREATE TABLE '{}' (
+                            id INTEGER PRIMARY KEY,
+                            file_path TEXT NOT NULL,
+                            hash TEXT NOT NULL,
+                            created TEXT,
+                            modified TEXT,
+                            accessed TEXT,
+                            manifest_id INTEGER NOT NULL,
+                            FOREIGN KEY (manifest_id) REFERENCES manifest (id)
+                        )
+                    "#,
+                    timestamp
+                ))
+                .unwrap();
+            for path in paths {
+                database
+                    .connection
+                    .execute(
+                        &format!(
+                            r#"
+                                INSERT INTO '{}' (file_path, hash, modified, manifest_id)
+                                VALUES (?1, ?2, ?3, ?4)
+                            "#,
+                            timestamp
+                        ),
+                        params![path, "0".repeat(64), modified, manifest_id],
+                    )
+                    .unwrap();
+            }
         }
-        Ok(())
+
+        database.migrate(&schemas).unwrap();
+
+        assert_eq!(
+            database.schema_version().unwrap(),
+            schemas.last().unwrap().0
+        );
+        assert!(!database.table_exists("1000").unwrap());
+        assert!(!database.table_exists("2000").unwrap());
+        let manifests: Vec<(i64, PathBuf, State)> = database
+            .select_manifests()
+            .unwrap()
+            .iter()
+            .map(|manifest| {
+                (
+                    manifest.id().0,
+                    manifest.file_path().to_path_buf(),
+                    manifest.state(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            manifests,
+            vec![
+                (1, PathBuf::from("/data"), State::Complete),
+                (2, PathBuf::from("/data/sub"), State::Complete),
+            ]
+        );
+        let entries = database.select_entries(&Id(1)).unwrap();
+        let paths: Vec<&Path> = entries.iter().map(|file| file.path()).collect();
+        assert_eq!(paths, vec![Path::new("/data/a"), Path::new("/data/sub/b")]);
+        assert!(entries
+            .iter()
+            .all(|file| file.modified() == Some(&modified)));
+        let stored: Vec<(i64, Vec<u8>)> = database
+            .connection
+            .prepare("SELECT manifest_id, file_path FROM entry ORDER BY id")
+            .unwrap()
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            stored,
+            vec![
+                (1, b"a".to_vec()),
+                (1, b"sub/b".to_vec()),
+                (2, b"b".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_scan_error_covers_its_path_and_what_is_below_it() {
+        let mut database = migrated();
+        let files = || {
+            vec![
+                file("/r/a/b", "1"),
+                file("/r/a/b/c", "2"),
+                file("/r/a/bc", "3"),
+            ]
+        };
+        let error =
+            ScanError::from_io_error(Path::new("/r/a/b"), &io::Error::from_raw_os_error(13));
+        let complete = manifest(&mut database, "/r", files());
+        let failed = manifest(&mut database, "/r", Vec::new());
+        database.insert_scan_errors(&failed, &[error]).unwrap();
+
+        assert_eq!(
+            summary(
+                database
+                    .select_manifest_differences(&failed, &complete, false)
+                    .unwrap()
+            ),
+            expected(&[
+                ("removed", "/r/a/bc"),
+                ("unreadable", "/r/a/b"),
+                ("unreadable", "/r/a/b/c"),
+            ])
+        );
+        // And back again, once the directory can be read
+        let recovered = manifest(&mut database, "/r", files());
+        assert_eq!(
+            summary(
+                database
+                    .select_manifest_differences(&recovered, &failed, false)
+                    .unwrap()
+            ),
+            expected(&[
+                ("added", "/r/a/bc"),
+                ("readable", "/r/a/b"),
+                ("readable", "/r/a/b/c"),
+            ])
+        );
+    }
+
+    #[test]
+    fn manifests_of_different_roots_match_only_by_relative_path() {
+        let mut database = migrated();
+        let created = |seconds| time::OffsetDateTime::from_unix_timestamp(seconds).ok();
+        let dated = |path: &str, hash: &str, seconds| {
+            FileMetadata::from_database(
+                PathBuf::from(path),
+                hash.to_string(),
+                created(seconds),
+                created(1_700_000_000),
+                None,
+                None,
+                Kind::File,
+            )
+            .unwrap()
+        };
+        // A copy of the tree made later, so every file was created anew
+        let old = manifest(
+            &mut database,
+            "/old",
+            vec![dated("/old/a", "1", 1), dated("/old/b", "2", 1)],
+        );
+        let new = manifest(
+            &mut database,
+            "/new",
+            vec![
+                dated("/new/a", "1", 2),
+                dated("/new/b", "3", 2),
+                dated("/new/c", "4", 2),
+            ],
+        );
+
+        assert_eq!(
+            summary(
+                database
+                    .select_manifest_differences(&new, &old, false)
+                    .unwrap()
+            ),
+            expected(&[
+                ("added", "/new/a"),
+                ("added", "/new/b"),
+                ("added", "/new/c"),
+                ("removed", "/old/a"),
+                ("removed", "/old/b"),
+            ])
+        );
+        assert_eq!(
+            summary(
+                database
+                    .select_manifest_differences(&new, &old, true)
+                    .unwrap()
+            ),
+            expected(&[("added", "/new/c"), ("hash", "/new/b")])
+        );
     }
 }
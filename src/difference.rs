@@ -1,4 +1,9 @@
-use crate::filemetadata::FileMetadata;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::filemetadata::{FileMetadata, Kind};
+use crate::scanerror::ScanError;
 
 #[derive(Debug)]
 pub enum Type {
@@ -8,6 +13,110 @@ pub enum Type {
     Delete(FileMetadata),
     // A hash mismatch
     Hash(i64, FileMetadata, i64, FileMetadata),
+    // A symbolic link points somewhere else, or replaced a file or was
+    // replaced by one
+    Target(i64, FileMetadata, i64, FileMetadata),
+    // The permission bits changed
+    Permission(i64, FileMetadata, i64, FileMetadata),
+    // The owning uid or gid changed
+    Ownership(i64, FileMetadata, i64, FileMetadata),
+    // The size changed
+    Size(i64, FileMetadata, i64, FileMetadata),
+    // The modified or created time changed
+    Timestamp(i64, FileMetadata, i64, FileMetadata),
+    // A file that was read before could not be read this time
+    Unreadable(i64, ScanError, i64, FileMetadata),
+    // A file that could not be read before was read this time
+    Readable(i64, FileMetadata, i64, ScanError),
+    // A file was removed and the same content added under another path
+    Moved {
+        from: FileMetadata,
+        to: FileMetadata,
+    },
+}
+
+impl Type {
+    /// The name of the kind of difference, as used in structured output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Type::Add(_) => "added",
+            Type::Delete(_) => "removed",
+            Type::Hash(..) => "hash",
+            Type::Target(..) => "target",
+            Type::Permission(..) => "permission",
+            Type::Ownership(..) => "ownership",
+            Type::Size(..) => "size",
+            Type::Timestamp(..) => "timestamp",
+            Type::Unreadable(..) => "unreadable",
+            Type::Readable(..) => "readable",
+            Type::Moved { .. } => "moved",
+        }
+    }
+    /// The path the difference is about; for a move, where the file went.
+    pub fn path(&self) -> &Path {
+        match self {
+            Type::Add(file) | Type::Delete(file) => file.path(),
+            Type::Hash(_, file, _, _)
+            | Type::Target(_, file, _, _)
+            | Type::Permission(_, file, _, _)
+            | Type::Ownership(_, file, _, _)
+            | Type::Size(_, file, _, _)
+            | Type::Timestamp(_, file, _, _)
+            | Type::Readable(_, file, _, _) => file.path(),
+            Type::Unreadable(_, error, _, _) => error.path(),
+            Type::Moved { to, .. } => to.path(),
+        }
+    }
+    /// The file as it is on the newer side, unless it is gone or unreadable.
+    pub fn new_file(&self) -> Option<&FileMetadata> {
+        match self {
+            Type::Add(file)
+            | Type::Hash(_, file, _, _)
+            | Type::Target(_, file, _, _)
+            | Type::Permission(_, file, _, _)
+            | Type::Ownership(_, file, _, _)
+            | Type::Size(_, file, _, _)
+            | Type::Timestamp(_, file, _, _)
+            | Type::Readable(_, file, _, _) => Some(file),
+            Type::Moved { to, .. } => Some(to),
+            Type::Delete(_) | Type::Unreadable(..) => None,
+        }
+    }
+}
+
+/// Manifest ids start at 1, so 0 stands for the live filesystem when a
+/// manifest is compared against a walk that was not stored.
+pub const LIVE_MANIFEST_ID: i64 = 0;
+
+struct ManifestLabel(i64);
+
+impl std::fmt::Display for ManifestLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 == LIVE_MANIFEST_ID {
+            write!(f, "Filesystem")
+        } else {
+            write!(f, "Manifest {}", self.0)
+        }
+    }
+}
+
+fn write_change(
+    f: &mut std::fmt::Formatter<'_>,
+    title: &str,
+    manifest_a: i64,
+    file_a: &dyn std::fmt::Display,
+    manifest_b: i64,
+    file_b: &dyn std::fmt::Display,
+) -> std::fmt::Result {
+    write!(
+        f,
+        "{}{}: {}\n{}: {}",
+        title,
+        ManifestLabel(manifest_a),
+        file_a,
+        ManifestLabel(manifest_b),
+        file_b,
+    )
 }
 
 impl std::fmt::Display for Type {
@@ -15,11 +124,331 @@ impl std::fmt::Display for Type {
         match self {
             Type::Add(file) => write!(f, "Added: {}", file),
             Type::Delete(file) => write!(f, "Removed: {}", file),
-            Type::Hash(manifest_a, file_a, manifest_b, file_b) => write!(
+            Type::Hash(manifest_a, file_a, manifest_b, file_b) => write_change(
+                f,
+                "Hash changed:\n",
+                *manifest_a,
+                file_a,
+                *manifest_b,
+                file_b,
+            ),
+            Type::Target(manifest_a, file_a, manifest_b, file_b) => write_change(
+                f,
+                "Link target changed:\n",
+                *manifest_a,
+                file_a,
+                *manifest_b,
+                file_b,
+            ),
+            Type::Permission(manifest_a, file_a, manifest_b, file_b) => write_change(
+                f,
+                "Permissions changed:\n",
+                *manifest_a,
+                file_a,
+                *manifest_b,
+                file_b,
+            ),
+            Type::Ownership(manifest_a, file_a, manifest_b, file_b) => write_change(
+                f,
+                "Ownership changed:\n",
+                *manifest_a,
+                file_a,
+                *manifest_b,
+                file_b,
+            ),
+            Type::Size(manifest_a, file_a, manifest_b, file_b) => write_change(
+                f,
+                "Size changed:\n",
+                *manifest_a,
+                file_a,
+                *manifest_b,
+                file_b,
+            ),
+            Type::Timestamp(manifest_a, file_a, manifest_b, file_b) => write_change(
+                f,
+                "Timestamps changed:\n",
+                *manifest_a,
+                file_a,
+                *manifest_b,
+                file_b,
+            ),
+            Type::Unreadable(manifest_a, error, manifest_b, file) => write_change(
                 f,
-                "Manifest {}: {}\nManifest {}: {}",
-                manifest_a, file_a, manifest_b, file_b,
+                "Became unreadable:\n",
+                *manifest_a,
+                error,
+                *manifest_b,
+                file,
             ),
+            Type::Readable(manifest_a, file, manifest_b, error) => write_change(
+                f,
+                "Became readable:\n",
+                *manifest_a,
+                file,
+                *manifest_b,
+                error,
+            ),
+            Type::Moved { from, to } => write!(f, "Moved: {}\nTo: {}", from, to),
+        }
+    }
+}
+
+/// Compare a set of files and the paths that could not be read against the
+/// entries and errors of a stored manifest, giving the same differences, in
+/// the same order, as `Database::select_manifest_differences`.
+pub fn compare(
+    new_id: i64,
+    new: &[FileMetadata],
+    new_errors: &[ScanError],
+    old_id: i64,
+    old: Vec<FileMetadata>,
+    old_errors: &[ScanError],
+) -> Vec<Type> {
+    // Ordered by the bytes of the path, as the database orders entries, so
+    // that the differences come out the same each run
+    let mut old: BTreeMap<OsString, FileMetadata> = old
+        .into_iter()
+        .map(|file| (file.path().as_os_str().to_owned(), file))
+        .collect();
+    let mut new: Vec<&FileMetadata> = new.iter().collect();
+    new.sort_by(|a, b| a.path().as_os_str().cmp(b.path().as_os_str()));
+    let mut hashes = Vec::new();
+    let mut targets = Vec::new();
+    let mut permissions = Vec::new();
+    let mut ownerships = Vec::new();
+    let mut sizes = Vec::new();
+    let mut timestamps = Vec::new();
+    let mut readable = Vec::new();
+    let mut added = Vec::new();
+    let covering = |errors: &[ScanError], file: &FileMetadata| {
+        errors
+            .iter()
+            .find(|error| error.covers(file.path()))
+            .cloned()
+    };
+    for file in new {
+        let previous = match old.remove(file.path().as_os_str()) {
+            Some(previous) => previous,
+            None => {
+                match covering(old_errors, file) {
+                    Some(error) => {
+                        readable.push(Type::Readable(new_id, file.clone(), old_id, error))
+                    }
+                    None => added.push(Type::Add(file.clone())),
+                }
+                continue;
+            }
+        };
+        let pair = || (new_id, file.clone(), old_id, previous.clone());
+        if file.kind() == &Kind::File && previous.kind() == &Kind::File {
+            if file.hash() != previous.hash() {
+                let (a, file_a, b, file_b) = pair();
+                hashes.push(Type::Hash(a, file_a, b, file_b));
+            }
+        } else if file.kind() != previous.kind() {
+            let (a, file_a, b, file_b) = pair();
+            targets.push(Type::Target(a, file_a, b, file_b));
+        }
+        if let (Some(stat), Some(previous_stat)) = (file.stat(), previous.stat()) {
+            if stat.mode() != previous_stat.mode() {
+                let (a, file_a, b, file_b) = pair();
+                permissions.push(Type::Permission(a, file_a, b, file_b));
+            }
+            if stat.uid() != previous_stat.uid() || stat.gid() != previous_stat.gid() {
+                let (a, file_a, b, file_b) = pair();
+                ownerships.push(Type::Ownership(a, file_a, b, file_b));
+            }
+            if stat.size() != previous_stat.size() {
+                let (a, file_a, b, file_b) = pair();
+                sizes.push(Type::Size(a, file_a, b, file_b));
+            }
+        }
+        // Like NULL in SQL, a missing time never differs
+        let changed = |a: Option<&time::OffsetDateTime>, b: Option<&time::OffsetDateTime>| matches!((a, b), (Some(a), Some(b)) if a != b);
+        if changed(file.modified(), previous.modified())
+            || changed(file.created(), previous.created())
+        {
+            let (a, file_a, b, file_b) = pair();
+            timestamps.push(Type::Timestamp(a, file_a, b, file_b));
+        }
+    }
+    let mut differences = hashes;
+    differences.extend(targets);
+    differences.extend(permissions);
+    differences.extend(ownerships);
+    differences.extend(sizes);
+    differences.extend(timestamps);
+    let mut removed = Vec::new();
+    for file in old.into_values() {
+        match covering(new_errors, &file) {
+            Some(error) => differences.push(Type::Unreadable(new_id, error, old_id, file)),
+            None => removed.push(Type::Delete(file)),
+        }
+    }
+    differences.extend(readable);
+    differences.extend(removed);
+    differences.extend(added);
+    pair_moves(differences)
+}
+
+/// Replace a `Delete` and an `Add` that share a hash with a single `Moved`.
+///
+/// Only content carried by exactly one removed and exactly one added file is
+/// paired, and only between files of the same kind and size. When several
+/// files share a hash it cannot be told which went where, or whether a copy
+/// was made, so those stay as plain adds and deletes. Empty files all share
+/// one hash, so they are never paired.
+pub fn pair_moves(differences: Vec<Type>) -> Vec<Type> {
+    let mut removed_contents: HashMap<Content, usize> = HashMap::new();
+    let mut added_contents: HashMap<Content, usize> = HashMap::new();
+    for difference in differences.iter() {
+        match difference {
+            Type::Delete(file) => *removed_contents.entry(content(file)).or_insert(0) += 1,
+            Type::Add(file) => *added_contents.entry(content(file)).or_insert(0) += 1,
+            _ => (),
+        }
+    }
+    let is_move = |file: &FileMetadata| {
+        let key = content(file);
+        key.1 != Some(0)
+            && removed_contents.get(&key) == Some(&1)
+            && added_contents.get(&key) == Some(&1)
+    };
+
+    let mut results = Vec::new();
+    let mut moved_from: HashMap<Content, FileMetadata> = HashMap::new();
+    let mut moved_to = Vec::new();
+    for difference in differences {
+        match difference {
+            Type::Delete(file) if is_move(&file) => {
+                moved_from.insert(content(&file), file);
+            }
+            Type::Add(file) if is_move(&file) => moved_to.push(file),
+            difference => results.push(difference),
+        }
+    }
+    for to in moved_to {
+        if let Some(from) = moved_from.remove(&content(&to)) {
+            results.push(Type::Moved { from, to });
+        }
+    }
+    results
+}
+
+/// What must match for a removed and an added file to be the same file:
+/// its kind, its size when known, and its hash.
+type Content = (&'static str, Option<u64>, String);
+
+fn content(file: &FileMetadata) -> Content {
+    (
+        file.kind().as_str(),
+        file.stat().map(|stat| stat.size()),
+        file.hash().to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filemetadata::Stat;
+    use std::path::PathBuf;
+
+    const HASH: &str = "98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4";
+
+    fn file(path: &str, size: i64, kind: Kind) -> FileMetadata {
+        let stat = Stat::from_database(size, 0o100644, 0, 0, 1, 1, 1, None);
+        FileMetadata::from_database(
+            PathBuf::from(path),
+            HASH.to_string(),
+            None,
+            None,
+            None,
+            Some(stat),
+            kind,
+        )
+        .unwrap()
+    }
+
+    fn kinds(differences: &[Type]) -> Vec<(&'static str, &Path)> {
+        differences
+            .iter()
+            .map(|difference| (difference.kind(), difference.path()))
+            .collect()
+    }
+
+    #[test]
+    fn unique_content_is_paired_as_a_move() {
+        let differences = pair_moves(vec![
+            Type::Delete(file("/r/a", 4, Kind::File)),
+            Type::Add(file("/r/b", 4, Kind::File)),
+        ]);
+        assert_eq!(kinds(&differences), vec![("moved", Path::new("/r/b"))]);
+    }
+
+    #[test]
+    fn shared_content_is_not_paired() {
+        let differences = pair_moves(vec![
+            Type::Delete(file("/r/a", 4, Kind::File)),
+            Type::Add(file("/r/b", 4, Kind::File)),
+            Type::Add(file("/r/c", 4, Kind::File)),
+        ]);
+        assert_eq!(
+            kinds(&differences),
+            vec![
+                ("removed", Path::new("/r/a")),
+                ("added", Path::new("/r/b")),
+                ("added", Path::new("/r/c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_files_are_not_paired() {
+        let differences = pair_moves(vec![
+            Type::Delete(file("/r/a", 0, Kind::File)),
+            Type::Add(file("/r/b", 0, Kind::File)),
+        ]);
+        assert_eq!(
+            kinds(&differences),
+            vec![("removed", Path::new("/r/a")), ("added", Path::new("/r/b"))]
+        );
+    }
+
+    #[test]
+    fn files_of_another_kind_or_size_are_not_paired() {
+        let link = Kind::Symlink(PathBuf::from("target"));
+        let differences = pair_moves(vec![
+            Type::Delete(file("/r/a", 4, Kind::File)),
+            Type::Add(file("/r/b", 4, link)),
+            Type::Delete(file("/r/c", 5, Kind::File)),
+            Type::Add(file("/r/d", 6, Kind::File)),
+        ]);
+        assert_eq!(
+            kinds(&differences),
+            vec![
+                ("removed", Path::new("/r/a")),
+                ("added", Path::new("/r/b")),
+                ("removed", Path::new("/r/c")),
+                ("added", Path::new("/r/d")),
+            ]
+        );
+    }
+
+    #[test]
+    fn every_change_starts_with_a_title() {
+        let (a, b) = (file("/r/a", 4, Kind::File), file("/r/a", 4, Kind::File));
+        let changes = [
+            Type::Hash(2, a.clone(), 1, b.clone()),
+            Type::Target(2, a.clone(), 1, b.clone()),
+            Type::Permission(2, a.clone(), 1, b.clone()),
+            Type::Ownership(2, a.clone(), 1, b.clone()),
+            Type::Size(2, a.clone(), 1, b.clone()),
+            Type::Timestamp(2, a, 1, b),
+        ];
+        for change in changes.iter() {
+            let shown = change.to_string();
+            let title = shown.lines().next().unwrap();
+            assert!(title.ends_with("changed:"), "{}", title);
         }
     }
 }
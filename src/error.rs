@@ -26,17 +26,41 @@ impl std::fmt::Display for SchemaFileProblem {
 
 #[derive(Debug)]
 pub enum Error {
+    ExchangeFormatOnly,
+    ExchangeFormatRequired,
+    // An entry that could not be read: the manifest id, the row id and why
+    CorruptEntry(i64, i64, Box<Error>),
+    // A daemon already answers on this socket
+    DaemonRunning(std::path::PathBuf),
     EmptyString,
+    IncompleteManifest(i64),
+    InvalidChecksumLine(usize),
+    InvalidDaemonConfig(usize),
+    InvalidDaemonReply(std::path::PathBuf),
+    InvalidFormat(String),
+    InvalidHashAlgorithm(String),
+    InvalidMtreeLine(usize),
+    InvalidSchedule(String),
     InvalidSchemaDirectory(std::path::PathBuf),
     InvalidSchemaFile(SchemaFileProblem),
+    InvalidSymlinkPolicy(String),
+    HashAlgorithmMismatch(i64, String, i64, String),
     IO(std::io::Error),
+    ManifestComplete(i64),
+    NoDaemon(std::path::PathBuf),
+    NoDatabasePath,
+    NoDigest(i64, String),
     NoFile(std::path::PathBuf),
+    NoMtreeDigest(std::path::PathBuf),
     ParseInt(std::num::ParseIntError),
+    Pattern(ignore::Error),
     NoSchemaFile(std::path::PathBuf),
     Rusqlite(rusqlite::Error),
-    SendPathBuf(std::sync::mpsc::SendError<std::path::PathBuf>),
-    SendPathBufHash(std::sync::mpsc::SendError<(std::path::PathBuf, String)>),
-    ThreadJoin,
+    SchemaTooNew(u16, u16),
+    // The result for this path could not be passed on, because the receiving
+    // side of the scan had already stopped
+    SendMessage(std::path::PathBuf),
+    ThreadJoin(String),
 }
 
 impl std::error::Error for Error {}
@@ -50,24 +74,103 @@ impl From<rusqlite::Error> for Error {
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let error_description = match self {
+            Error::CorruptEntry(manifest, row, e) => {
+                format!("Entry {} of manifest {} is corrupt: {}", row, manifest, e)
+            }
+            Error::DaemonRunning(socket) => {
+                format!("A daemon is already listening on {:?}", socket)
+            }
             Error::EmptyString => String::from("An empty string was provided"),
+            Error::ExchangeFormatOnly => String::from(
+                "The b3sum, sha256sum and mtree formats are only for export and import",
+            ),
+            Error::ExchangeFormatRequired => {
+                String::from("Import needs --format b3sum, sha256sum or mtree")
+            }
+            Error::IncompleteManifest(id) => format!(
+                "Manifest {} is not complete, resume it or pass --force to use it anyway",
+                id
+            ),
+            Error::InvalidChecksumLine(line) => {
+                format!(
+                    "Line {} is not in sha256sum or b3sum format, or names a path outside the root",
+                    line
+                )
+            }
+            Error::InvalidDaemonConfig(line) => format!(
+                "Line {} of the daemon configuration is not a schedule, a number of manifests to keep and an absolute root",
+                line
+            ),
+            Error::InvalidDaemonReply(socket) => {
+                format!("The daemon on {:?} sent a reply that could not be read", socket)
+            }
+            Error::InvalidFormat(format) => format!(
+                "Unknown output format: {}, expected text, json, ndjson, b3sum, sha256sum or mtree",
+                format
+            ),
+            Error::InvalidHashAlgorithm(algorithm) => format!(
+                "Unknown hash algorithm: {}, expected blake3, sha256, sha1 or md5",
+                algorithm
+            ),
+            Error::InvalidMtreeLine(line) => {
+                format!("Line {} is not a valid mtree specification line", line)
+            }
+            Error::InvalidSchedule(schedule) => format!(
+                "Invalid schedule: {}, expected @every with a count of s, m, h or d, @hourly, @daily, @weekly, @monthly or five cron fields",
+                schedule
+            ),
             Error::InvalidSchemaDirectory(path) => {
                 format!("The provided pathbuf: {:?} is invalid", path)
             }
             Error::InvalidSchemaFile(problem) => {
                 format!("A provided schema file is invalid: {}", problem)
             }
+            Error::InvalidSymlinkPolicy(policy) => format!(
+                "Unknown symlink policy: {}, expected record, follow or skip",
+                policy
+            ),
+            Error::HashAlgorithmMismatch(first, first_algorithm, second, second_algorithm) => {
+                format!(
+                    "Manifest {} is hashed with {} but manifest {} with {}, so their hashes cannot be compared",
+                    first, first_algorithm, second, second_algorithm
+                )
+            }
             Error::IO(e) => format!("An IO Error occurred: {}", e),
+            Error::ManifestComplete(id) => {
+                format!(
+                    "Manifest {} is already complete, there is nothing to resume",
+                    id
+                )
+            }
+            Error::NoDigest(manifest, algorithm) => format!(
+                "Manifest {} has no {} digests, create it with --hash {}",
+                manifest, algorithm, algorithm
+            ),
+            Error::NoDaemon(socket) => format!("No daemon is listening on {:?}", socket),
+            Error::NoDatabasePath => {
+                String::from("No database path was given and neither XDG_DATA_HOME nor HOME is set")
+            }
             Error::NoFile(path) => format!("The file provided does not exist: {:?}", path),
+            Error::NoMtreeDigest(path) => format!(
+                "The mtree specification has no sha256, sha1 or md5 digest for {:?}, create it with -K sha256digest",
+                path
+            ),
             Error::ParseInt(e) => format!(
                 "While parsing a String to an Integer, an error occured: {}",
                 e
             ),
+            Error::Pattern(e) => format!("An invalid include or exclude pattern: {}", e),
             Error::NoSchemaFile(path) => format!("No schema files found at: {:?}", path),
             Error::Rusqlite(e) => format!("A rusqlite error occurred: {}", e),
-            Error::SendPathBuf(e) => format!("A Send error occurred: {}", e),
-            Error::SendPathBufHash(e) => format!("A send derror occurred: {}", e),
-            Error::ThreadJoin => String::from("An error occurred from a thread"),
+            Error::SchemaTooNew(found, supported) => format!(
+                "The database uses schema version {}, but only up to {} is supported",
+                found, supported
+            ),
+            Error::SendMessage(path) => format!(
+                "The scan stopped before the result for {:?} could be passed on",
+                path
+            ),
+            Error::ThreadJoin(message) => format!("A scan thread panicked: {}", message),
         };
         write!(f, "{}", error_description)
     }
@@ -85,20 +188,22 @@ impl From<std::num::ParseIntError> for Error {
     }
 }
 
-impl From<std::sync::mpsc::SendError<std::path::PathBuf>> for Error {
-    fn from(e: std::sync::mpsc::SendError<std::path::PathBuf>) -> Self {
-        Error::SendPathBuf(e)
-    }
-}
-
-impl From<std::sync::mpsc::SendError<(std::path::PathBuf, String)>> for Error {
-    fn from(e: std::sync::mpsc::SendError<(std::path::PathBuf, String)>) -> Self {
-        Error::SendPathBufHash(e)
+impl From<ignore::Error> for Error {
+    fn from(e: ignore::Error) -> Self {
+        Error::Pattern(e)
     }
 }
 
 impl From<Box<dyn std::any::Any + Send + 'static>> for Error {
-    fn from(_: Box<dyn std::any::Any + Send + 'static>) -> Self {
-        Error::ThreadJoin
+    fn from(payload: Box<dyn std::any::Any + Send + 'static>) -> Self {
+        // panic! gives a &str for a literal message and a String otherwise
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => String::from("unknown panic payload"),
+            },
+        };
+        Error::ThreadJoin(message)
     }
 }
@@ -0,0 +1,29 @@
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Render a path for people and for JSON without losing anything. Bytes
+/// that are not valid UTF-8, such as legacy Latin-1 names, and control
+/// characters become `\xNN`, and a backslash becomes `\\`, so distinct
+/// paths never print the same.
+pub fn escape_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                c if c.is_control() => {
+                    let mut buffer = [0; 4];
+                    for byte in c.encode_utf8(&mut buffer).bytes() {
+                        let _ = write!(escaped, "\\x{:02x}", byte);
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "\\x{:02x}", byte);
+        }
+    }
+    escaped
+}
@@ -1,82 +1,283 @@
 use std::fmt;
 use std::fs;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
 use crate::error::Error;
+use crate::escape::escape_path;
+use crate::hash::{Algorithm, Hasher};
 
 const READ_MAX: usize = 4098 * 1024;
 
-#[derive(Debug)]
+/// The parts of a file's stat record that are kept besides its timestamps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    inode: u64,
+    device: u64,
+    nlink: u64,
+    // The inode change time, which entries recorded before it was kept lack
+    changed: Option<time::OffsetDateTime>,
+}
+
+impl Stat {
+    fn from_metadata(metadata: &fs::Metadata) -> Self {
+        Stat {
+            size: metadata.len(),
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            inode: metadata.ino(),
+            device: metadata.dev(),
+            nlink: metadata.nlink(),
+            changed: time::OffsetDateTime::from_unix_timestamp_nanos(
+                metadata.ctime() as i128 * 1_000_000_000 + metadata.ctime_nsec() as i128,
+            )
+            .ok(),
+        }
+    }
+    // SQLite only has signed integers, so the values are stored bit for bit.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_database(
+        size: i64,
+        mode: i64,
+        uid: i64,
+        gid: i64,
+        inode: i64,
+        device: i64,
+        nlink: i64,
+        changed: Option<time::OffsetDateTime>,
+    ) -> Self {
+        Stat {
+            size: size as u64,
+            mode: mode as u32,
+            uid: uid as u32,
+            gid: gid as u32,
+            inode: inode as u64,
+            device: device as u64,
+            nlink: nlink as u64,
+            changed,
+        }
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+    pub fn inode(&self) -> u64 {
+        self.inode
+    }
+    pub fn device(&self) -> u64 {
+        self.device
+    }
+    pub fn nlink(&self) -> u64 {
+        self.nlink
+    }
+    pub fn changed(&self) -> Option<&time::OffsetDateTime> {
+        self.changed.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    File,
+    // A symbolic link recorded as itself, with the path it points to
+    Symlink(PathBuf),
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Symlink(_) => "symlink",
+        }
+    }
+    pub fn link_target(&self) -> Option<&Path> {
+        match self {
+            Kind::File => None,
+            Kind::Symlink(target) => Some(target),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct FileMetadata {
     path: PathBuf,
     hash: String,
-    created: time::OffsetDateTime,
-    modified: time::OffsetDateTime,
-    accessed: time::OffsetDateTime,
+    created: Option<time::OffsetDateTime>,
+    // Entries imported from a checksum file have no times
+    modified: Option<time::OffsetDateTime>,
+    accessed: Option<time::OffsetDateTime>,
+    // Entries recorded before the stat columns existed have none
+    stat: Option<Stat>,
+    kind: Kind,
+    // Digests by the manifest's further algorithms; `hash` is by the first
+    digests: Vec<(Algorithm, String)>,
 }
 
 impl FileMetadata {
-    pub fn from_pathbuf(path: &Path) -> Result<Self, Error> {
+    /// Describe a file, hashing it with every one of `algorithms`.
+    pub fn from_pathbuf(path: &Path, algorithms: &[Algorithm]) -> Result<Self, Error> {
+        FileMetadata::from_pathbuf_with_previous(path, algorithms, None)
+    }
+    /// Like `from_pathbuf`, but when `previous` describes the same file with
+    /// the same size, inode, modified time and change time, its hashes are
+    /// reused and the content is not read.
+    pub fn from_pathbuf_with_previous(
+        path: &Path,
+        algorithms: &[Algorithm],
+        previous: Option<&FileMetadata>,
+    ) -> Result<Self, Error> {
         if !path.is_file() {
             return Err(Error::NoFile(path.to_path_buf()));
         }
-        let file = fs::File::open(&path)?;
-        let hash = FileMetadata::calculate_hash(&file)?;
-        let (created, modified, accessed) = FileMetadata::times(&file)?;
+        let metadata = fs::metadata(path)?;
+        let stat = Stat::from_metadata(&metadata);
+        let (created, modified, accessed) = FileMetadata::times(&metadata)?;
+        let (hash, digests) = match previous {
+            Some(previous) if previous.unchanged(&stat, &modified, algorithms) => {
+                (previous.hash.clone(), previous.digests.clone())
+            }
+            _ => FileMetadata::calculate_hash(&fs::File::open(path)?, algorithms)?,
+        };
         Ok(FileMetadata {
             path: path.to_path_buf(),
             hash,
             created,
-            modified,
-            accessed,
+            modified: Some(modified),
+            accessed: Some(accessed),
+            stat: Some(stat),
+            kind: Kind::File,
+            digests,
+        })
+    }
+    fn unchanged(
+        &self,
+        stat: &Stat,
+        modified: &time::OffsetDateTime,
+        algorithms: &[Algorithm],
+    ) -> bool {
+        let previous = match (&self.kind, &self.stat) {
+            (Kind::File, Some(previous)) => previous,
+            _ => return false,
+        };
+        let further = self.digests.iter().map(|(algorithm, _)| algorithm);
+        further.eq(algorithms.iter().skip(1))
+            && previous.changed.is_some()
+            && previous.changed == stat.changed
+            && previous.size == stat.size
+            && previous.inode == stat.inode
+            && previous.device == stat.device
+            && self.modified.as_ref() == Some(modified)
+    }
+    /// Describe a symbolic link without following it. The hashes are taken
+    /// over the target path, so a retargeted link no longer matches.
+    pub fn from_symlink(path: &Path, algorithms: &[Algorithm]) -> Result<Self, Error> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.file_type().is_symlink() {
+            return Err(Error::NoFile(path.to_path_buf()));
+        }
+        let target = fs::read_link(path)?;
+        let mut hasher = Hasher::new(algorithms);
+        hasher.update(target.as_os_str().as_bytes());
+        let (hash, digests) = FileMetadata::split_digests(algorithms, hasher.finalize());
+        let (created, modified, accessed) = FileMetadata::times(&metadata)?;
+        Ok(FileMetadata {
+            path: path.to_path_buf(),
+            hash,
+            created,
+            modified: Some(modified),
+            accessed: Some(accessed),
+            stat: Some(Stat::from_metadata(&metadata)),
+            kind: Kind::Symlink(target),
+            digests,
         })
     }
     pub fn from_database(
-        path: String,
+        path: PathBuf,
         hash: String,
-        created: time::OffsetDateTime,
-        modified: time::OffsetDateTime,
-        accessed: time::OffsetDateTime,
+        created: Option<time::OffsetDateTime>,
+        modified: Option<time::OffsetDateTime>,
+        accessed: Option<time::OffsetDateTime>,
+        stat: Option<Stat>,
+        kind: Kind,
     ) -> Result<Self, Error> {
-        if path.is_empty() {
+        if path.as_os_str().is_empty() {
             return Err(Error::EmptyString);
         }
         if hash.is_empty() {
             return Err(Error::EmptyString);
         }
         Ok(FileMetadata {
-            path: Path::new(&path).to_path_buf(),
+            path,
             hash,
             created,
             modified,
             accessed,
+            stat,
+            kind,
+            digests: Vec::new(),
         })
     }
-    fn calculate_hash(file: &fs::File) -> Result<String, Error> {
+    /// An entry known only by its path and hash, as listed in a checksum
+    /// file.
+    pub fn from_hash(path: PathBuf, hash: String) -> Result<Self, Error> {
+        FileMetadata::from_database(path, hash, None, None, None, None, Kind::File)
+    }
+    /// Attach the digests stored besides the entry's hash.
+    pub fn with_digests(mut self, digests: Vec<(Algorithm, String)>) -> Self {
+        self.digests = digests;
+        self
+    }
+    fn calculate_hash(
+        file: &fs::File,
+        algorithms: &[Algorithm],
+    ) -> Result<(String, Vec<(Algorithm, String)>), Error> {
         // Thanks to erer1243 on #beginners discord for the shorter code!
         let mut reader = BufReader::with_capacity(READ_MAX, file);
 
-        let mut hasher = blake3::Hasher::new();
-        std::io::copy(&mut reader, &mut hasher)?;
-        Ok(hasher.finalize().to_string())
+        let mut hasher = Hasher::new(algorithms);
+        io::copy(&mut reader, &mut hasher)?;
+        Ok(FileMetadata::split_digests(algorithms, hasher.finalize()))
+    }
+    /// The first digest becomes the hash; the rest are kept by algorithm.
+    fn split_digests(
+        algorithms: &[Algorithm],
+        mut digests: Vec<String>,
+    ) -> (String, Vec<(Algorithm, String)>) {
+        let further = digests.split_off(digests.len().min(1));
+        let hash = digests.pop().unwrap_or_default();
+        let further = algorithms.iter().skip(1).copied().zip(further).collect();
+        (hash, further)
     }
     fn times(
-        file: &fs::File,
+        metadata: &fs::Metadata,
     ) -> Result<
         (
-            time::OffsetDateTime,
+            Option<time::OffsetDateTime>,
             time::OffsetDateTime,
             time::OffsetDateTime,
         ),
         Error,
     > {
-        let metadata = file.metadata()?;
-        let st_created = metadata.created()?;
+        // Not every filesystem records a birth time
+        let st_created = metadata.created().ok();
         let st_modified = metadata.modified()?;
         let st_accessed = metadata.accessed()?;
         Ok((
-            time::OffsetDateTime::from(st_created),
+            st_created.map(time::OffsetDateTime::from),
             time::OffsetDateTime::from(st_modified),
             time::OffsetDateTime::from(st_accessed),
         ))
@@ -87,27 +288,53 @@ impl FileMetadata {
     pub fn hash(&self) -> &str {
         &self.hash
     }
-    pub fn created(&self) -> &time::OffsetDateTime {
-        &self.created
+    pub fn created(&self) -> Option<&time::OffsetDateTime> {
+        self.created.as_ref()
+    }
+    pub fn modified(&self) -> Option<&time::OffsetDateTime> {
+        self.modified.as_ref()
     }
-    pub fn modified(&self) -> &time::OffsetDateTime {
-        &self.modified
+    pub fn accessed(&self) -> Option<&time::OffsetDateTime> {
+        self.accessed.as_ref()
     }
-    pub fn accessed(&self) -> &time::OffsetDateTime {
-        &self.accessed
+    pub fn stat(&self) -> Option<&Stat> {
+        self.stat.as_ref()
+    }
+    pub fn kind(&self) -> &Kind {
+        &self.kind
+    }
+    pub fn digests(&self) -> &[(Algorithm, String)] {
+        &self.digests
     }
 }
 
 impl fmt::Display for FileMetadata {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Path: {}, Hash: {}, Created: {}, Modified: {}, Accessed: {}",
-            self.path.display(),
-            self.hash,
-            self.created,
-            self.modified,
-            self.accessed,
-        )
+        write!(f, "Path: {}, Hash: {}", escape_path(&self.path), self.hash)?;
+        for (algorithm, digest) in &self.digests {
+            write!(f, ", {}: {}", algorithm.as_str(), digest)?;
+        }
+        if let Kind::Symlink(target) = &self.kind {
+            write!(f, ", Target: {}", escape_path(target))?;
+        }
+        if let Some(stat) = &self.stat {
+            write!(
+                f,
+                ", Size: {}, Mode: {:o}, Owner: {}:{}",
+                stat.size, stat.mode, stat.uid, stat.gid,
+            )?;
+        }
+        let times = [
+            ("Created", &self.created),
+            ("Modified", &self.modified),
+            ("Accessed", &self.accessed),
+        ];
+        for (label, time) in times.iter() {
+            match time {
+                Some(time) => write!(f, ", {}: {}", label, time)?,
+                None => write!(f, ", {}: unknown", label)?,
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,120 @@
+use std::io;
+
+use sha2::Digest;
+
+use crate::error::Error;
+
+/// A digest algorithm a manifest can hash its files with.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Algorithm {
+    #[default]
+    Blake3,
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl Algorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Md5 => "md5",
+        }
+    }
+    /// The length of a digest in hex.
+    pub fn digest_length(&self) -> usize {
+        match self {
+            Algorithm::Blake3 | Algorithm::Sha256 => 64,
+            Algorithm::Sha1 => 40,
+            Algorithm::Md5 => 32,
+        }
+    }
+    /// Parse a comma separated list, as stored on the manifest row.
+    pub fn parse_list(s: &str) -> Result<Vec<Algorithm>, Error> {
+        s.split(',').map(str::parse).collect()
+    }
+    pub fn format_list(algorithms: &[Algorithm]) -> String {
+        algorithms
+            .iter()
+            .map(Algorithm::as_str)
+            .collect::<Vec<&str>>()
+            .join(",")
+    }
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(Algorithm::Blake3),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha1" => Ok(Algorithm::Sha1),
+            "md5" => Ok(Algorithm::Md5),
+            _ => Err(Error::InvalidHashAlgorithm(s.to_string())),
+        }
+    }
+}
+
+enum State {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+    Sha1(sha1::Sha1),
+    Md5(md5::Md5),
+}
+
+/// Computes the digests of several algorithms in a single pass over the
+/// content.
+pub struct Hasher {
+    states: Vec<State>,
+}
+
+impl Hasher {
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        let states = algorithms
+            .iter()
+            .map(|algorithm| match algorithm {
+                Algorithm::Blake3 => State::Blake3(Box::new(blake3::Hasher::new())),
+                Algorithm::Sha256 => State::Sha256(sha2::Sha256::new()),
+                Algorithm::Sha1 => State::Sha1(sha1::Sha1::new()),
+                Algorithm::Md5 => State::Md5(md5::Md5::new()),
+            })
+            .collect();
+        Hasher { states }
+    }
+    pub fn update(&mut self, bytes: &[u8]) {
+        for state in self.states.iter_mut() {
+            match state {
+                State::Blake3(hasher) => {
+                    hasher.update(bytes);
+                }
+                State::Sha256(hasher) => hasher.update(bytes),
+                State::Sha1(hasher) => hasher.update(bytes),
+                State::Md5(hasher) => hasher.update(bytes),
+            }
+        }
+    }
+    /// The hex digests, in the order the algorithms were given.
+    pub fn finalize(self) -> Vec<String> {
+        self.states
+            .into_iter()
+            .map(|state| match state {
+                State::Blake3(hasher) => hasher.finalize().to_string(),
+                State::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+                State::Sha1(hasher) => format!("{:x}", hasher.finalize()),
+                State::Md5(hasher) => format!("{:x}", hasher.finalize()),
+            })
+            .collect()
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.update(bytes);
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
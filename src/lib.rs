@@ -1,91 +1,496 @@
+mod checksum;
+mod daemon;
 mod database;
 use database::Database;
 mod difference;
+use difference::LIVE_MANIFEST_ID;
 pub mod error;
+mod escape;
 use error::Error;
 mod filemetadata;
+use filemetadata::FileMetadata;
+pub mod hash;
+use hash::Algorithm;
 pub mod manifest;
-use manifest::Timestamp;
+use manifest::{Id, Manifest, State, Timestamp};
+mod mtree;
 pub mod operation;
-use operation::Operation;
+pub mod output;
+use operation::{Operation, Outcome, Settings};
+use output::{display_failures, display_result, Format};
+pub mod rules;
+use rules::Rules;
+mod scanerror;
 mod scanner;
-use scanner::Scanner;
+use scanerror::ScanError;
+use scanner::{Scan, Scanner};
+mod schedule;
 mod schema;
-use schema::read_schemas;
-use std::path::Path;
-
-const DB_PATH: &str = "testing.sqlite";
-const SCHEMA_DIR: &str = "schema";
-
-fn display_result<I, T>(iterator: I)
-where
-    I: Iterator<Item = T>,
-    T: std::fmt::Display,
-{
-    for item in iterator {
-        println!("{}", item);
+use schema::embedded_schemas;
+mod watch;
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use watch::Watch;
+
+const DB_ENV: &str = "SCANNER_DB";
+const DB_DIR: &str = "scanner";
+const DB_FILE: &str = "scanner.sqlite";
+
+/// Pick the database path: an explicit path wins, then `SCANNER_DB`, then
+/// `$XDG_DATA_HOME/scanner/`, falling back to `~/.local/share/scanner/`.
+pub fn database_path(explicit: Option<&Path>) -> Result<PathBuf, Error> {
+    if let Some(path) = explicit {
+        return Ok(path.to_path_buf());
     }
+    if let Some(path) = env::var_os(DB_ENV).filter(|path| !path.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+    let data_home = match env::var_os("XDG_DATA_HOME").filter(|path| !path.is_empty()) {
+        Some(path) => PathBuf::from(path),
+        None => match env::var_os("HOME").filter(|path| !path.is_empty()) {
+            Some(home) => Path::new(&home).join(".local").join("share"),
+            None => return Err(Error::NoDatabasePath),
+        },
+    };
+    Ok(data_home.join(DB_DIR).join(DB_FILE))
 }
 
-pub fn get_database() -> Result<Database, Error> {
-    let path = Path::new(DB_PATH);
-    let database = match path.is_file() {
-        true => Database::open(path)?,
-        false => {
-            if let Some(schemas) = read_schemas(Path::new(SCHEMA_DIR))? {
-                Database::create(path, schemas.iter())?
-            } else {
-                return Err(Error::NoSchemaFile(Path::new(SCHEMA_DIR).to_path_buf()));
-            }
+pub fn get_database(path: &Path) -> Result<Database, Error> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
         }
+    }
+    let schemas = embedded_schemas()?;
+    let database = match path.is_file() {
+        true => Database::open(path, &schemas)?,
+        false => Database::create(path, &schemas)?,
     };
     Ok(database)
 }
 
-pub fn run(operation: Operation) -> Result<(), Error> {
-    let mut database = get_database()?;
-    match operation {
-        Operation::Compare(first, second) => {
+/// Store a scan's files as they are hashed and the paths it could not read,
+/// then mark the manifest complete, or aborted if the scan fails. Returns the
+/// paths that could not be read.
+fn record_scan(
+    database: &mut Database,
+    manifest_id: &Id,
+    mut scan: Scan,
+) -> Result<Vec<ScanError>, Error> {
+    let result = database
+        .insert_file_paths_and_hashes(manifest_id, scan.by_ref())
+        .and_then(|_| scan.finish());
+    match result {
+        Ok(failures) => {
+            database.insert_scan_errors(manifest_id, &failures)?;
+            database.update_manifest_state(manifest_id, State::Complete)?;
+            Ok(failures)
+        }
+        Err(e) => {
+            database.update_manifest_state(manifest_id, State::Aborted)?;
+            Err(e)
+        }
+    }
+}
+
+/// Scan a new root into a new manifest. Returns the manifest and the paths
+/// that could not be read.
+fn index(
+    database: &mut Database,
+    settings: Settings,
+    path: PathBuf,
+    rules: Rules,
+    algorithms: Vec<Algorithm>,
+) -> Result<(Id, Vec<ScanError>), Error> {
+    let rules = rules.with_ignore_file(&path)?;
+    let scanner = Scanner::new(path, rules, algorithms, settings.jobs)?;
+    let manifest_id = database.create_manifest(
+        &Timestamp::now(),
+        scanner.root(),
+        scanner.rules(),
+        scanner.algorithms(),
+    )?;
+    let failures = record_scan(database, &manifest_id, scanner.scan())?;
+    Ok((manifest_id, failures))
+}
+
+/// Rescan a manifest's root into a new manifest and compare the two. Unless
+/// `paranoid` is set, the hashes of files whose stat is unchanged are
+/// carried forward. Returns the new manifest, the differences and the paths
+/// that could not be read.
+fn rescan(
+    database: &mut Database,
+    settings: Settings,
+    manifest: &Manifest,
+    paranoid: bool,
+) -> Result<(Id, Vec<difference::Type>, Vec<ScanError>), Error> {
+    let rules = database.select_manifest_rules(&manifest.id())?;
+    let mut scanner = Scanner::new(
+        manifest.file_path().to_path_buf(),
+        rules,
+        manifest.algorithms().to_vec(),
+        settings.jobs,
+    )?;
+    if !paranoid {
+        let previous = database
+            .select_entries(&manifest.id())?
+            .into_iter()
+            .map(|file| (file.path().to_path_buf(), file))
+            .collect();
+        scanner = scanner.with_previous(Arc::new(previous));
+    }
+    let new_manifest_id = database.create_manifest(
+        &Timestamp::now(),
+        scanner.root(),
+        scanner.rules(),
+        scanner.algorithms(),
+    )?;
+    let failures = record_scan(database, &new_manifest_id, scanner.scan())?;
+    let differences = database
+        .select_manifest_differences(&new_manifest_id, &manifest.id(), false)?
+        .unwrap_or_default();
+    Ok((new_manifest_id, differences, failures))
+}
+
+/// Refuse to use a manifest whose scan did not complete unless forced, as
+/// every path it never reached would look added.
+fn check_complete(manifest: &Manifest, force: bool) -> Result<(), Error> {
+    if !force && manifest.state() != State::Complete {
+        return Err(Error::IncompleteManifest(manifest.id().0));
+    }
+    Ok(())
+}
+
+pub fn run(
+    database_path: &Path,
+    settings: Settings,
+    operation: Operation,
+) -> Result<Outcome, Error> {
+    let format = settings.format;
+    let exchange = matches!(operation, Operation::Export(_) | Operation::Import(..));
+    if format.exchange() && !exchange {
+        return Err(Error::ExchangeFormatOnly);
+    }
+    let mut database = get_database(database_path)?;
+    let outcome = match operation {
+        Operation::Compare(first, second, force, relative) => {
             let new_record = database.select_manifest(&first)?;
             let old_record = database.select_manifest(&second)?;
-            if let Some(differences) = database
-                .select_manifest_differences(&new_record.timestamp(), &old_record.timestamp())?
-            {
-                display_result(differences.into_iter());
-            } else {
-                println!("Sets match.");
+            check_complete(&new_record, force)?;
+            check_complete(&old_record, force)?;
+            // Entries are matched by the hash of each manifest's first algorithm
+            let new_algorithm = new_record.algorithms().first().copied().unwrap_or_default();
+            let old_algorithm = old_record.algorithms().first().copied().unwrap_or_default();
+            if new_algorithm != old_algorithm {
+                return Err(Error::HashAlgorithmMismatch(
+                    new_record.id().0,
+                    new_algorithm.as_str().to_string(),
+                    old_record.id().0,
+                    old_algorithm.as_str().to_string(),
+                ));
             }
+            let differences = database
+                .select_manifest_differences(&new_record.id(), &old_record.id(), relative)?
+                .unwrap_or_default();
+            let outcome = Outcome::from_differences(&differences, 0);
+            display_result(format, differences.into_iter(), None, Some("Sets match."));
+            outcome
+        }
+        Operation::Daemon(config, socket) => {
+            let jobs = daemon::parse_config(&fs::read_to_string(&config)?)?;
+            let socket = socket.unwrap_or_else(|| daemon::socket_path(database_path));
+            daemon::run(database_path, settings, jobs, &socket)?;
+            Outcome::NoDifferences
         }
         Operation::DeleteManifest(manifest_id) => {
-            database.delete_manifest_drop_table(&manifest_id)?;
+            database.delete_manifest(&manifest_id)?;
+            Outcome::NoDifferences
+        }
+        Operation::Export(manifest_id) => {
+            let manifest = database.select_manifest(&manifest_id)?;
+            let mut files = database.select_entries(&manifest.id())?;
+            files.sort_by(|a, b| a.path().cmp(b.path()));
+            if format.exchange() {
+                let stdout = io::stdout();
+                let mut writer = BufWriter::new(stdout.lock());
+                match format.checksum_algorithm() {
+                    Some(algorithm) => checksum::export(&mut writer, &manifest, &files, algorithm)?,
+                    None => mtree::export(&mut writer, &manifest, &files)?,
+                }
+                writer.flush()?;
+            } else {
+                display_result(format, files.into_iter(), None, None);
+            }
+            Outcome::NoDifferences
         }
-        Operation::Index(path) => {
-            let scanner = Scanner::new(path)?;
-            let results = scanner.index()?;
-            let manifest = Timestamp::now();
-            database.create_manifest_table(&manifest, scanner.root())?;
-            database.insert_file_paths_and_hashes(&manifest, results.into_iter())?;
+        Operation::Import(file, root) => {
+            let (algorithms, files) = match (format, format.checksum_algorithm()) {
+                (Format::Mtree, _) => mtree::parse(&fs::read(&file)?, &root)?,
+                (_, Some(algorithm)) => {
+                    let lines = checksum::parse(&fs::read(&file)?, algorithm.digest_length())?;
+                    let files = lines
+                        .into_iter()
+                        .map(|(path, hash)| {
+                            FileMetadata::from_hash(checksum::resolve(&root, &path), hash)
+                        })
+                        .collect::<Result<Vec<FileMetadata>, Error>>()?;
+                    (vec![algorithm], files)
+                }
+                _ => return Err(Error::ExchangeFormatRequired),
+            };
+            let manifest_id = database.create_manifest(
+                &Timestamp::now(),
+                &root,
+                &Rules::default(),
+                &algorithms,
+            )?;
+            database.insert_file_paths_and_hashes(&manifest_id, files.into_iter())?;
+            database.update_manifest_state(&manifest_id, State::Complete)?;
+            Outcome::NoDifferences
+        }
+        Operation::Index(path, rules, algorithms) => {
+            let (_, failures) = index(&mut database, settings, path, rules, algorithms)?;
+            display_failures(format, &failures);
+            Outcome::new(false, failures.len())
         }
         Operation::List => {
             let manifests = database.select_manifests()?;
-            println!("id\ttimestamp\tpath");
-            display_result(manifests.into_iter());
+            display_result(
+                format,
+                manifests.into_iter(),
+                Some("id\ttimestamp\tstate\tpath"),
+                None,
+            );
+            Outcome::NoDifferences
         }
-        Operation::Scan(manifest_id) => {
+        Operation::Resume(manifest_id) => {
             let manifest = database.select_manifest(&manifest_id)?;
-            let scanner = Scanner::new(manifest.file_path().to_path_buf())?;
-            let results = scanner.index()?;
-            let new_manifest = Timestamp::now();
-            database.create_manifest_table(&new_manifest, scanner.root())?;
-            database.insert_file_paths_and_hashes(&new_manifest, results.into_iter())?;
-            if let Some(differences) =
-                database.select_manifest_differences(&new_manifest, &manifest.timestamp())?
-            {
-                display_result(differences.into_iter());
-            } else {
-                println!("Sets match.");
+            if manifest.state() == State::Complete {
+                return Err(Error::ManifestComplete(manifest.id().0));
             }
+            let rules = database.select_manifest_rules(&manifest.id())?;
+            let recorded = database
+                .select_entry_paths(&manifest.id())?
+                .into_iter()
+                .collect();
+            let scanner = Scanner::new(
+                manifest.file_path().to_path_buf(),
+                rules,
+                manifest.algorithms().to_vec(),
+                settings.jobs,
+            )?
+            .with_skip(Arc::new(recorded));
+            database.update_manifest_state(&manifest.id(), State::InProgress)?;
+            let failures = record_scan(&mut database, &manifest.id(), scanner.scan())?;
+            display_failures(format, &failures);
+            Outcome::new(false, failures.len())
+        }
+        Operation::Scan(manifest_id, paranoid, force) => {
+            let manifest = database.select_manifest(&manifest_id)?;
+            check_complete(&manifest, force)?;
+            let (_, differences, failures) = rescan(&mut database, settings, &manifest, paranoid)?;
+            let outcome = Outcome::from_differences(&differences, failures.len());
+            display_result(format, differences.into_iter(), None, Some("Sets match."));
+            display_failures(format, &failures);
+            outcome
+        }
+        Operation::Status(socket) => {
+            let socket = socket.unwrap_or_else(|| daemon::socket_path(database_path));
+            let statuses = daemon::query(&socket)?;
+            display_result(
+                format,
+                statuses.into_iter(),
+                Some("schedule\tkeep\tstate\tlast_run\tresult\tnext_run\troot"),
+                None,
+            );
+            Outcome::NoDifferences
+        }
+        Operation::Verify(manifest_id, save_on_change, force) => {
+            let manifest = database.select_manifest(&manifest_id)?;
+            check_complete(&manifest, force)?;
+            let rules = database.select_manifest_rules(&manifest.id())?;
+            let scanner = Scanner::new(
+                manifest.file_path().to_path_buf(),
+                rules,
+                manifest.algorithms().to_vec(),
+                settings.jobs,
+            )?;
+            let (results, failures) = scanner.index()?;
+            let stored = database.select_entries(&manifest.id())?;
+            let stored_failures = database.select_scan_errors(&manifest.id())?;
+            let mut differences = difference::compare(
+                LIVE_MANIFEST_ID,
+                &results,
+                &failures,
+                manifest.id().0,
+                stored,
+                &stored_failures,
+            );
+            if save_on_change && !differences.is_empty() {
+                let new_manifest_id = database.create_manifest(
+                    &Timestamp::now(),
+                    scanner.root(),
+                    scanner.rules(),
+                    scanner.algorithms(),
+                )?;
+                database.insert_file_paths_and_hashes(&new_manifest_id, results.into_iter())?;
+                database.insert_scan_errors(&new_manifest_id, &failures)?;
+                database.update_manifest_state(&new_manifest_id, State::Complete)?;
+                differences = database
+                    .select_manifest_differences(&new_manifest_id, &manifest.id(), false)?
+                    .unwrap_or_default();
+            }
+            let outcome = Outcome::from_differences(&differences, failures.len());
+            display_result(format, differences.into_iter(), None, Some("Sets match."));
+            display_failures(format, &failures);
+            outcome
+        }
+        Operation::Watch(manifest_id, record, force) => {
+            let manifest = database.select_manifest(&manifest_id)?;
+            check_complete(&manifest, force)?;
+            let rules = database.select_manifest_rules(&manifest.id())?;
+            let mut watch = Watch::new(
+                manifest.file_path().to_path_buf(),
+                &rules,
+                manifest.algorithms().to_vec(),
+                manifest.id().0,
+                database.select_entries(&manifest.id())?,
+            )?;
+            loop {
+                let differences = watch.next_differences()?;
+                if record {
+                    database.insert_watch_changes(
+                        &manifest.id(),
+                        &Timestamp::now(),
+                        &differences,
+                    )?;
+                }
+                display_result(format, differences.into_iter(), None, None);
+            }
+        }
+    };
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::INSERT_BATCH;
+    use rusqlite::{params, Connection};
+
+    /// A root of `count` small files with a database beside it, removed
+    /// again once the test is done.
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str, count: usize) -> Tree {
+            let directory =
+                env::temp_dir().join(format!("scanner-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&directory);
+            fs::create_dir_all(directory.join("root")).unwrap();
+            for number in 0..count {
+                let path = directory.join("root").join(number.to_string());
+                fs::write(path, number.to_string()).unwrap();
+            }
+            Tree(directory)
+        }
+        fn root(&self) -> PathBuf {
+            self.0.join("root")
+        }
+        fn database(&self) -> PathBuf {
+            self.0.join("scanner.sqlite")
+        }
+        fn hashes(&self, manifest_id: i64) -> Vec<String> {
+            let connection = Connection::open(self.database()).unwrap();
+            let mut statement = connection
+                .prepare("SELECT hash FROM entry WHERE manifest_id = ?1")
+                .unwrap();
+            let hashes = statement
+                .query_map(params![manifest_id], |row| row.get(0))
+                .unwrap();
+            hashes.collect::<Result<Vec<String>, _>>().unwrap()
         }
     }
-    Ok(())
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn settings() -> Settings {
+        Settings::new(Format::Text, Some(4))
+    }
+
+    #[test]
+    fn scan_of_more_than_a_batch_reuses_digests() {
+        let tree = Tree::new("scan", INSERT_BATCH + 100);
+        let index = Operation::Index(tree.root(), Rules::default(), Vec::new());
+        run(&tree.database(), settings(), index).unwrap();
+        // A hash no file has shows which digests were carried forward
+        let carried = "0".repeat(64);
+        Connection::open(tree.database())
+            .unwrap()
+            .execute("UPDATE entry SET hash = ?1", params![carried])
+            .unwrap();
+        let scan = Operation::Scan(Id(1), false, false);
+        assert_eq!(
+            run(&tree.database(), settings(), scan).unwrap(),
+            Outcome::NoDifferences
+        );
+        let hashes = tree.hashes(2);
+        assert_eq!(hashes.len(), INSERT_BATCH + 100);
+        assert!(hashes.iter().all(|hash| *hash == carried));
+    }
+
+    #[test]
+    fn resume_of_more_than_a_batch_records_each_path_once() {
+        let tree = Tree::new("resume", INSERT_BATCH + 100);
+        let index = Operation::Index(tree.root(), Rules::default(), Vec::new());
+        run(&tree.database(), settings(), index).unwrap();
+        // Leave it as a scan killed after its first batch would
+        let connection = Connection::open(tree.database()).unwrap();
+        connection
+            .execute(
+                "DELETE FROM entry WHERE id > ?1",
+                params![INSERT_BATCH as i64],
+            )
+            .unwrap();
+        connection
+            .execute("UPDATE manifest SET state = 'aborted'", params![])
+            .unwrap();
+        let resume = Operation::Resume(Id(1));
+        assert_eq!(
+            run(&tree.database(), settings(), resume).unwrap(),
+            Outcome::NoDifferences
+        );
+        let (entries, paths, state): (i64, i64, String) = connection
+            .query_row(
+                "SELECT count(*), count(DISTINCT file_path), (SELECT state FROM manifest) FROM entry",
+                params![],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (entries, paths),
+            (INSERT_BATCH as i64 + 100, INSERT_BATCH as i64 + 100)
+        );
+        assert_eq!(state, "complete");
+    }
+
+    #[test]
+    fn scan_of_a_missing_root_is_partial() {
+        let tree = Tree::new("missing", 3);
+        let index = Operation::Index(tree.root(), Rules::default(), Vec::new());
+        run(&tree.database(), settings(), index).unwrap();
+        fs::rename(tree.root(), tree.0.join("moved")).unwrap();
+        let scan = Operation::Scan(Id(1), false, false);
+        assert_eq!(
+            run(&tree.database(), settings(), scan).unwrap(),
+            Outcome::PartialScan
+        );
+    }
 }
@@ -1,9 +1,28 @@
 use clap::Parser;
-use scanner::{error::Error, manifest::Id, operation::Operation, run};
-use std::path::Path;
+use scanner::{
+    database_path,
+    hash::Algorithm,
+    manifest::Id,
+    operation::{Operation, Settings, FATAL_EXIT_CODE},
+    output::Format,
+    rules::{Rules, SymlinkPolicy},
+    run,
+};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 #[derive(Parser)]
 struct Opts {
+    /// The database to use, overriding SCANNER_DB and the XDG data directory
+    #[clap(long, global = true, parse(from_os_str), allow_invalid_utf8 = true)]
+    db: Option<PathBuf>,
+    /// The output format: text, json or ndjson, or b3sum, sha256sum or mtree
+    /// for export and import
+    #[clap(long, global = true, default_value = "text")]
+    format: Format,
+    /// The number of hashing threads, one per CPU by default
+    #[clap(long, global = true)]
+    jobs: Option<usize>,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -11,10 +30,17 @@ struct Opts {
 #[derive(Parser)]
 enum SubCommand {
     Compare(Compare),
+    Daemon(Daemon),
     Create(Create),
     Delete(Delete),
+    Export(Export),
+    Import(Import),
     List,
+    Resume(Resume),
     Scan(Scan),
+    Status(Status),
+    Verify(Verify),
+    Watch(Watch),
 }
 
 /// Compare two manifests and note any differences
@@ -26,14 +52,34 @@ struct Compare {
     /// The second manifest id
     #[clap(short, long)]
     second: i64,
+    /// Compare even if a manifest's scan did not complete
+    #[clap(long)]
+    force: bool,
+    /// Match entries by their path below each root, for manifests of the
+    /// same tree taken at different locations
+    #[clap(long)]
+    relative: bool,
 }
 
 /// Scan a path, creating a new manifest
 #[derive(Parser)]
 struct Create {
     /// The path to start the scan
-    #[clap(short, long)]
-    path: String,
+    #[clap(short, long, parse(from_os_str))]
+    path: PathBuf,
+    /// Only record files matching this gitignore-style pattern
+    #[clap(long, multiple_occurrences = true)]
+    include: Vec<String>,
+    /// Skip files and directories matching this gitignore-style pattern
+    #[clap(long, multiple_occurrences = true)]
+    exclude: Vec<String>,
+    /// What to do with symbolic links: record, follow or skip
+    #[clap(long, default_value = "record")]
+    symlinks: SymlinkPolicy,
+    /// Hash with blake3, sha256, sha1 or md5; repeat to store several
+    /// digests, the first of which is used to compare manifests
+    #[clap(long, multiple_occurrences = true)]
+    hash: Vec<Algorithm>,
 }
 
 /// Delete an existing manifest
@@ -44,32 +90,168 @@ struct Delete {
     manifest: i64,
 }
 
+/// Scan roots on the schedules of a configuration file until stopped, one
+/// at a time
+#[derive(Parser)]
+struct Daemon {
+    /// One job per line: a schedule such as "@every 6h" or "0 3 * * *" in
+    /// UTC, how many of the root's manifests to keep, and the absolute root
+    #[clap(short, long, parse(from_os_str))]
+    config: PathBuf,
+    /// The socket to serve status on, next to the database by default
+    #[clap(long, parse(from_os_str))]
+    socket: Option<PathBuf>,
+}
+
+/// Write a manifest's entries, as a checksum file with --format b3sum or
+/// sha256sum, or as an mtree specification with --format mtree
+#[derive(Parser)]
+struct Export {
+    /// The manifest to export
+    #[clap(short, long)]
+    manifest: i64,
+}
+
+/// Create a manifest from a checksum file given with --format b3sum or
+/// sha256sum, or from an mtree specification given with --format mtree
+#[derive(Parser)]
+struct Import {
+    /// The checksum file or specification to read
+    #[clap(short, long, parse(from_os_str))]
+    file: PathBuf,
+    /// The directory the paths in the file are relative to
+    #[clap(short, long, parse(from_os_str))]
+    root: PathBuf,
+}
+
 /// List existing manifests
 #[derive(Parser)]
 struct List {}
 
+/// Continue an interrupted scan, skipping paths already recorded
+#[derive(Parser)]
+struct Resume {
+    /// The incomplete manifest to continue
+    #[clap(short, long)]
+    manifest: i64,
+}
+
 /// Re-run a scan, create a new manifest and note any differences
 #[derive(Parser)]
 struct Scan {
     /// Rerun a scan, create a new manifest and compare the results
     #[clap(short, long)]
     manifest: i64,
+    /// Rehash every file, even those whose size, times and inode are unchanged
+    #[clap(long)]
+    paranoid: bool,
+    /// Use the manifest even if its scan did not complete
+    #[clap(long)]
+    force: bool,
 }
 
-fn main() -> Result<(), Error> {
-    let opts = Opts::parse();
-    let operation = match opts.subcmd {
-        SubCommand::Compare(compare_matches) => {
-            Operation::Compare(Id(compare_matches.first), Id(compare_matches.second))
+/// Show what a running daemon's jobs are doing
+#[derive(Parser)]
+struct Status {
+    /// The daemon's socket, next to the database by default
+    #[clap(long, parse(from_os_str))]
+    socket: Option<PathBuf>,
+}
+
+/// Check the filesystem against a manifest without storing a new one
+#[derive(Parser)]
+struct Verify {
+    /// The manifest to check the filesystem against
+    #[clap(short, long)]
+    manifest: i64,
+    /// Store a new manifest when differences were found
+    #[clap(long)]
+    save_on_change: bool,
+    /// Use the manifest even if its scan did not complete
+    #[clap(long)]
+    force: bool,
+}
+
+/// Report changes under a manifest's root as they happen, until interrupted
+#[derive(Parser)]
+struct Watch {
+    /// The manifest whose root to watch and whose entries to start from
+    #[clap(short, long)]
+    manifest: i64,
+    /// Record the changes in the database
+    #[clap(long)]
+    record: bool,
+    /// Use the manifest even if its scan did not complete
+    #[clap(long)]
+    force: bool,
+}
+
+fn main() -> ExitCode {
+    let opts = match Opts::try_parse() {
+        Ok(opts) => opts,
+        Err(e) => {
+            // Usage errors must not be mistaken for a partial scan; help and
+            // version requests are not errors at all
+            let _ = e.print();
+            return match e.use_stderr() {
+                true => ExitCode::from(FATAL_EXIT_CODE),
+                false => ExitCode::SUCCESS,
+            };
         }
-        SubCommand::Create(create_matches) => {
-            Operation::Index(Path::new(&create_matches.path).to_path_buf())
+    };
+    let operation = match opts.subcmd {
+        SubCommand::Compare(compare_matches) => Operation::Compare(
+            Id(compare_matches.first),
+            Id(compare_matches.second),
+            compare_matches.force,
+            compare_matches.relative,
+        ),
+        SubCommand::Create(create_matches) => Operation::Index(
+            create_matches.path,
+            Rules::new(
+                create_matches.include,
+                create_matches.exclude,
+                create_matches.symlinks,
+            ),
+            create_matches.hash,
+        ),
+        SubCommand::Daemon(daemon_matches) => {
+            Operation::Daemon(daemon_matches.config, daemon_matches.socket)
         }
         SubCommand::Delete(delete_matches) => {
             Operation::DeleteManifest(Id(delete_matches.manifest))
         }
+        SubCommand::Export(export_matches) => Operation::Export(Id(export_matches.manifest)),
+        SubCommand::Import(import_matches) => {
+            Operation::Import(import_matches.file, import_matches.root)
+        }
         SubCommand::List => Operation::List,
-        SubCommand::Scan(scan_matches) => Operation::Scan(Id(scan_matches.manifest)),
+        SubCommand::Resume(resume_matches) => Operation::Resume(Id(resume_matches.manifest)),
+        SubCommand::Scan(scan_matches) => Operation::Scan(
+            Id(scan_matches.manifest),
+            scan_matches.paranoid,
+            scan_matches.force,
+        ),
+        SubCommand::Status(status_matches) => Operation::Status(status_matches.socket),
+        SubCommand::Verify(verify_matches) => Operation::Verify(
+            Id(verify_matches.manifest),
+            verify_matches.save_on_change,
+            verify_matches.force,
+        ),
+        SubCommand::Watch(watch_matches) => Operation::Watch(
+            Id(watch_matches.manifest),
+            watch_matches.record,
+            watch_matches.force,
+        ),
     };
-    run(operation)
+    let settings = Settings::new(opts.format, opts.jobs);
+    let result = database_path(opts.db.as_deref())
+        .and_then(|database_path| run(&database_path, settings, operation));
+    match result {
+        Ok(outcome) => ExitCode::from(outcome.exit_code()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(FATAL_EXIT_CODE)
+        }
+    }
 }
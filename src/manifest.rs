@@ -1,3 +1,6 @@
+use crate::escape::escape_path;
+use crate::hash::Algorithm;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use std::path::{Path, PathBuf};
 use time::OffsetDateTime;
 
@@ -9,7 +12,44 @@ pub struct Timestamp(pub i64);
 impl Timestamp {
     pub fn now() -> Self {
         let unix_timestamp = OffsetDateTime::now_utc().unix_timestamp();
-        Timestamp { 0: unix_timestamp }
+        Timestamp(unix_timestamp)
+    }
+}
+
+/// Whether a manifest's scan ran to the end. Entries are committed while
+/// the scan runs, so a killed scan leaves an in-progress manifest behind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    InProgress,
+    Complete,
+    // The scan stopped on an error
+    Aborted,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::InProgress => "in-progress",
+            State::Complete => "complete",
+            State::Aborted => "aborted",
+        }
+    }
+}
+
+impl FromSql for State {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "in-progress" => Ok(State::InProgress),
+            "complete" => Ok(State::Complete),
+            "aborted" => Ok(State::Aborted),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for State {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
     }
 }
 
@@ -17,15 +57,25 @@ pub struct Manifest {
     id: i64,
     timestamp: i64,
     file_path: PathBuf,
+    state: State,
+    // The first algorithm gives each entry's hash
+    algorithms: Vec<Algorithm>,
 }
 
 impl Manifest {
-    pub fn from_database(id: Id, timestamp: Timestamp, file_path: String) -> Self {
-        let file_path = Path::new(&file_path).to_path_buf();
+    pub fn from_database(
+        id: Id,
+        timestamp: Timestamp,
+        file_path: PathBuf,
+        state: State,
+        algorithms: Vec<Algorithm>,
+    ) -> Self {
         Manifest {
             id: id.0,
             timestamp: timestamp.0,
             file_path,
+            state,
+            algorithms,
         }
     }
     pub fn id(&self) -> Id {
@@ -37,16 +87,23 @@ impl Manifest {
     pub fn file_path(&self) -> &Path {
         &self.file_path
     }
+    pub fn state(&self) -> State {
+        self.state
+    }
+    pub fn algorithms(&self) -> &[Algorithm] {
+        &self.algorithms
+    }
 }
 
 impl std::fmt::Display for Manifest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}\t{}\t{}",
+            "{}\t{}\t{}\t{}",
             self.id,
             self.timestamp,
-            self.file_path.display()
+            self.state.as_str(),
+            escape_path(&self.file_path)
         )
     }
 }
@@ -0,0 +1,508 @@
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::Error;
+use crate::filemetadata::{FileMetadata, Kind, Stat};
+use crate::hash::{Algorithm, Hasher};
+use crate::manifest::Manifest;
+
+// The file type bits of a mode, which a specification gives as its type
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const PERMISSION_BITS: u32 = 0o7777;
+
+// The digest keywords of the algorithms mtree knows, strongest first, each
+// with its short alias. BLAKE3 has none.
+const DIGEST_KEYWORDS: [(Algorithm, &str, &str); 3] = [
+    (Algorithm::Sha256, "sha256digest", "sha256"),
+    (Algorithm::Sha1, "sha1digest", "sha1"),
+    (Algorithm::Md5, "md5digest", "md5"),
+];
+
+/// Write a manifest as an mtree specification in full path form, with an
+/// entry for each directory leading to a file, so `mtree -f` can check a
+/// tree against it. A manifest with no digest mtree knows is refused, as
+/// its specification could not check content.
+pub fn export<W: Write>(
+    writer: &mut W,
+    manifest: &Manifest,
+    files: &[FileMetadata],
+) -> Result<(), Error> {
+    let exportable = manifest.algorithms().iter().any(|algorithm| {
+        DIGEST_KEYWORDS
+            .iter()
+            .any(|(known, _, _)| known == algorithm)
+    });
+    if !exportable {
+        return Err(Error::NoDigest(
+            manifest.id().0,
+            Algorithm::Sha256.as_str().to_string(),
+        ));
+    }
+    let first = manifest.algorithms().first().copied().unwrap_or_default();
+    writeln!(writer, "#mtree")?;
+    writeln!(writer, ". type=dir")?;
+    let mut directories = HashSet::new();
+    for file in files {
+        let path = file
+            .path()
+            .strip_prefix(manifest.file_path())
+            .unwrap_or_else(|_| file.path());
+        let parents: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .collect();
+        for parent in parents.into_iter().rev() {
+            if directories.insert(parent.to_path_buf()) {
+                writeln!(writer, "./{} type=dir", encode(parent.as_os_str()))?;
+            }
+        }
+        write!(writer, "./{}", encode(path.as_os_str()))?;
+        match file.kind() {
+            Kind::File => write!(writer, " type=file")?,
+            Kind::Symlink(target) => {
+                write!(writer, " type=link link={}", encode(target.as_os_str()))?
+            }
+        }
+        if let Some(stat) = file.stat() {
+            write!(
+                writer,
+                " mode={:04o} uid={} gid={} size={}",
+                stat.mode() & PERMISSION_BITS,
+                stat.uid(),
+                stat.gid(),
+                stat.size()
+            )?;
+        }
+        if let Some(modified) = file.modified() {
+            write!(
+                writer,
+                " time={}.{:09}",
+                modified.unix_timestamp(),
+                modified.nanosecond()
+            )?;
+        }
+        // The hash of a symbolic link is over its target, not content
+        if file.kind() == &Kind::File {
+            let digests = std::iter::once((first, file.hash())).chain(
+                file.digests()
+                    .iter()
+                    .map(|(algorithm, digest)| (*algorithm, digest.as_str())),
+            );
+            for (algorithm, digest) in digests {
+                if let Some((_, keyword, _)) = DIGEST_KEYWORDS
+                    .iter()
+                    .find(|(known, _, _)| *known == algorithm)
+                {
+                    write!(writer, " {}={}", keyword, digest)?;
+                }
+            }
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Read an mtree specification into the files and symbolic links it
+/// describes under `root`, with the algorithms of the digests every file
+/// carries. Both the hierarchical form `mtree -c` writes and the full path
+/// form are read; directories and other types are passed over.
+pub fn parse(content: &[u8], root: &Path) -> Result<(Vec<Algorithm>, Vec<FileMetadata>), Error> {
+    let mut defaults: BTreeMap<String, String> = BTreeMap::new();
+    let mut current = PathBuf::new();
+    let mut described = Vec::new();
+    for (number, line) in logical_lines(content) {
+        let invalid = || Error::InvalidMtreeLine(number);
+        let mut tokens = line
+            .split(|byte| *byte == b' ' || *byte == b'\t')
+            .filter(|token| !token.is_empty());
+        let name = match tokens.next() {
+            Some(name) if !name.starts_with(b"#") => name,
+            _ => continue,
+        };
+        match name {
+            b"/set" => {
+                for token in tokens {
+                    let (keyword, value) = keyword(token).ok_or_else(invalid)?;
+                    defaults.insert(keyword, value);
+                }
+                continue;
+            }
+            b"/unset" => {
+                for token in tokens {
+                    match token {
+                        b"all" => defaults.clear(),
+                        keyword => {
+                            defaults.remove(String::from_utf8_lossy(keyword).as_ref());
+                        }
+                    }
+                }
+                continue;
+            }
+            // The last one closes the root, which there is no leaving
+            b".." => {
+                current.pop();
+                continue;
+            }
+            _ => (),
+        }
+        let name = PathBuf::from(OsStr::from_bytes(&decode(name).ok_or_else(invalid)?));
+        let mut keywords = defaults.clone();
+        for token in tokens {
+            let (keyword, value) = keyword(token).ok_or_else(invalid)?;
+            keywords.insert(keyword, value);
+        }
+        // A name with a slash is a full path and leaves the current directory
+        let full = name.as_os_str().as_bytes().contains(&b'/');
+        let path: PathBuf = if full {
+            name.components()
+                .filter(|component| *component != Component::CurDir)
+                .collect()
+        } else if name == Path::new(".") {
+            current.clone()
+        } else {
+            current.join(&name)
+        };
+        match keywords.get("type").map(String::as_str).unwrap_or("file") {
+            "dir" if !full => current = path,
+            "file" | "link" => described.push((number, path, keywords)),
+            _ => (),
+        }
+    }
+
+    let files = described
+        .iter()
+        .filter(|(_, _, keywords)| keywords.get("type").map(String::as_str) != Some("link"));
+    let algorithms: Vec<Algorithm> = DIGEST_KEYWORDS
+        .iter()
+        .filter(|(_, keyword, alias)| {
+            files.clone().all(|(_, _, keywords)| {
+                keywords.contains_key(*keyword) || keywords.contains_key(*alias)
+            })
+        })
+        .map(|(algorithm, _, _)| *algorithm)
+        .collect();
+    if algorithms.is_empty() {
+        if let Some((_, path, _)) = files.clone().next() {
+            return Err(Error::NoMtreeDigest(root.join(path)));
+        }
+    }
+    let algorithms = match algorithms.is_empty() {
+        true => vec![Algorithm::Sha256],
+        false => algorithms,
+    };
+
+    let mut entries = Vec::with_capacity(described.len());
+    for (number, path, keywords) in described {
+        let invalid = || Error::InvalidMtreeLine(number);
+        let (kind, mut digests) = match keywords.get("type").map(String::as_str) {
+            Some("link") => {
+                let target = keywords.get("link").ok_or_else(invalid)?;
+                let target = PathBuf::from(OsStr::from_bytes(
+                    &decode(target.as_bytes()).ok_or_else(invalid)?,
+                ));
+                let mut hasher = Hasher::new(&algorithms);
+                hasher.update(target.as_os_str().as_bytes());
+                (Kind::Symlink(target), hasher.finalize())
+            }
+            _ => {
+                let digests = algorithms
+                    .iter()
+                    .map(|algorithm| digest(&keywords, *algorithm).ok_or_else(invalid))
+                    .collect::<Result<Vec<String>, Error>>()?;
+                (Kind::File, digests)
+            }
+        };
+        let stat = stat(&keywords, &kind).map_err(|_| invalid())?;
+        let modified = match keywords.get("time") {
+            Some(time) => Some(parse_time(time).ok_or_else(invalid)?),
+            None => None,
+        };
+        let further = digests.split_off(1);
+        let hash = digests.pop().unwrap_or_default();
+        let further = algorithms.iter().skip(1).copied().zip(further).collect();
+        let entry =
+            FileMetadata::from_database(root.join(path), hash, None, modified, None, stat, kind)?
+                .with_digests(further);
+        entries.push(entry);
+    }
+    Ok((algorithms, entries))
+}
+
+/// The lines of a specification with their numbers, a line ending in a
+/// backslash being continued on the next.
+fn logical_lines(content: &[u8]) -> Vec<(usize, Vec<u8>)> {
+    let mut lines = Vec::new();
+    let mut pending: Option<(usize, Vec<u8>)> = None;
+    for (number, line) in content.split(|byte| *byte == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (number, mut joined) = pending.take().unwrap_or((number + 1, Vec::new()));
+        match line.strip_suffix(b"\\") {
+            Some(line) => {
+                joined.extend_from_slice(line);
+                joined.push(b' ');
+                pending = Some((number, joined));
+            }
+            None => {
+                joined.extend_from_slice(line);
+                lines.push((number, joined));
+            }
+        }
+    }
+    lines.extend(pending);
+    lines
+}
+
+fn keyword(token: &[u8]) -> Option<(String, String)> {
+    let token = std::str::from_utf8(token).ok()?;
+    match token.split_once('=') {
+        Some((keyword, value)) => Some((keyword.to_string(), value.to_string())),
+        // Flags such as optional or nochange carry no value
+        None => Some((token.to_string(), String::new())),
+    }
+}
+
+fn digest(keywords: &BTreeMap<String, String>, algorithm: Algorithm) -> Option<String> {
+    let (_, keyword, alias) = DIGEST_KEYWORDS
+        .iter()
+        .find(|(known, _, _)| *known == algorithm)?;
+    let digest = keywords.get(*keyword).or_else(|| keywords.get(*alias))?;
+    let valid = digest.len() == algorithm.digest_length()
+        && digest.bytes().all(|byte| byte.is_ascii_hexdigit());
+    match valid {
+        true => Some(digest.to_ascii_lowercase()),
+        false => None,
+    }
+}
+
+/// A stat from the mode, uid, gid and size keywords, when all are given.
+/// Specifications carry no inode or device, so those are left at zero.
+fn stat(keywords: &BTreeMap<String, String>, kind: &Kind) -> Result<Option<Stat>, Error> {
+    let (mode, uid, gid, size) = match (
+        keywords.get("mode"),
+        keywords.get("uid"),
+        keywords.get("gid"),
+        keywords.get("size"),
+    ) {
+        (Some(mode), Some(uid), Some(gid), Some(size)) => (mode, uid, gid, size),
+        _ => return Ok(None),
+    };
+    let mode = u32::from_str_radix(mode, 8)?;
+    let mode = match kind {
+        Kind::File => S_IFREG | (mode & PERMISSION_BITS),
+        Kind::Symlink(_) => S_IFLNK | (mode & PERMISSION_BITS),
+    };
+    let nlink = match keywords.get("nlink") {
+        Some(nlink) => nlink.parse::<i64>()?,
+        None => 1,
+    };
+    Ok(Some(Stat::from_database(
+        size.parse::<u64>()? as i64,
+        mode as i64,
+        uid.parse::<u32>()? as i64,
+        gid.parse::<u32>()? as i64,
+        0,
+        0,
+        nlink,
+        None,
+    )))
+}
+
+/// Parse a `seconds.nanoseconds` time. As in BSD mtree and libarchive, the
+/// part after the dot is a count of nanoseconds, not a decimal fraction, so
+/// `1.5` is five nanoseconds past the second.
+fn parse_time(time: &str) -> Option<time::OffsetDateTime> {
+    let (seconds, nanoseconds) = time.split_once('.').unwrap_or((time, "0"));
+    if nanoseconds.is_empty() || !nanoseconds.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let seconds: i128 = seconds.parse().ok()?;
+    let nanoseconds: i128 = nanoseconds.parse().ok().filter(|n| *n < 1_000_000_000)?;
+    time::OffsetDateTime::from_unix_timestamp_nanos(seconds * 1_000_000_000 + nanoseconds).ok()
+}
+
+/// Encode a name the way `mtree -c` does: bytes outside printable ASCII,
+/// and backslashes and glob characters, as a backslash and three octal
+/// digits.
+fn encode(name: &OsStr) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.as_bytes() {
+        match byte {
+            b'\\' | b'#' | b'*' | b'?' | b'[' => encoded.push_str(&format!("\\{:03o}", byte)),
+            0x21..=0x7e => encoded.push(*byte as char),
+            _ => encoded.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    encoded
+}
+
+fn decode(name: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(name.len());
+    let mut bytes = name.iter().peekable();
+    while let Some(byte) = bytes.next() {
+        if *byte != b'\\' {
+            decoded.push(*byte);
+            continue;
+        }
+        match bytes.next()? {
+            digit @ b'0'..=b'7' => {
+                let mut value = u32::from(digit - b'0');
+                for _ in 0..2 {
+                    match bytes.next_if(|byte| matches!(byte, b'0'..=b'7')) {
+                        Some(digit) => value = value * 8 + u32::from(digit - b'0'),
+                        None => return None,
+                    }
+                }
+                decoded.push(u8::try_from(value).ok()?);
+            }
+            b'n' => decoded.push(b'\n'),
+            b'r' => decoded.push(b'\r'),
+            b't' => decoded.push(b'\t'),
+            b's' => decoded.push(b' '),
+            other => decoded.push(*other),
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    const SHA1: &str = "e5fa44f2b31c1fb553b6021e7360d07d5d91ff5e";
+
+    #[test]
+    fn names_round_trip_through_encoding() {
+        let names: [&[u8]; 4] = [
+            b"plain.txt",
+            b"with space",
+            b"back\\slash #1 *?[",
+            b"line\nbreak \xff\x01",
+        ];
+        for name in names.iter() {
+            let encoded = encode(OsStr::from_bytes(name));
+            assert!(encoded.bytes().all(|byte| (0x21..=0x7e).contains(&byte)));
+            assert_eq!(decode(encoded.as_bytes()).as_deref(), Some(*name));
+        }
+        assert_eq!(encode(OsStr::new("a b")), "a\\040b");
+    }
+
+    #[test]
+    fn names_decode_escapes() {
+        assert_eq!(decode(b"a\\sb\\tc\\n").unwrap(), b"a b\tc\n");
+        assert_eq!(decode(b"\\134\\\\").unwrap(), b"\\\\");
+        assert_eq!(decode(b"\\1"), None);
+        assert_eq!(decode(b"\\400"), None);
+        assert_eq!(decode(b"trailing\\"), None);
+    }
+
+    #[test]
+    fn continued_lines_are_joined() {
+        let lines = logical_lines(b"one \\\n  two\nthree\r\nfour\\");
+        let lines: Vec<(usize, &[u8])> = lines
+            .iter()
+            .map(|(number, line)| (*number, line.as_slice()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (1, &b"one    two"[..]),
+                (3, &b"three"[..]),
+                (4, &b"four "[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn hierarchical_form_is_read() {
+        let spec = format!(
+            "#mtree\n\
+             /set type=file uid=0 gid=0 mode=0644\n\
+             . type=dir\n\
+             a\\040b size=2 time=1.5 \\\n    sha256digest={sha256} sha1={sha1}\n\
+             sub type=dir mode=0755\n\
+             \x20   link type=link link=../a\\040b\n\
+             \x20   x size=3 sha256={sha256} sha1digest={sha1}\n\
+             ..\n\
+             /unset all\n\
+             y type=file sha256={sha256} sha1={sha1}\n\
+             ..\n",
+            sha256 = SHA256,
+            sha1 = SHA1
+        );
+        let (algorithms, files) = parse(spec.as_bytes(), Path::new("/root")).unwrap();
+        assert_eq!(algorithms, vec![Algorithm::Sha256, Algorithm::Sha1]);
+        let paths: Vec<&Path> = files.iter().map(FileMetadata::path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("/root/a b"),
+                Path::new("/root/sub/link"),
+                Path::new("/root/sub/x"),
+                Path::new("/root/y"),
+            ]
+        );
+
+        let first = &files[0];
+        assert_eq!(first.hash(), SHA256);
+        assert_eq!(first.digests(), &[(Algorithm::Sha1, SHA1.to_string())]);
+        let stat = first.stat().unwrap();
+        assert_eq!((stat.mode(), stat.uid(), stat.size()), (0o100644, 0, 2));
+        assert_eq!(first.modified().unwrap().nanosecond(), 5);
+
+        assert_eq!(files[1].kind().link_target(), Some(Path::new("../a b")));
+        // The directory's own keywords are not defaults for what it holds
+        assert_eq!(files[2].stat().unwrap().mode(), 0o100644);
+        // Nothing is left to give a mode after /unset
+        assert!(files[3].stat().is_none());
+    }
+
+    #[test]
+    fn full_path_form_is_read() {
+        let spec = format!(
+            "./d type=dir\n./d/e type=file sha256digest={}\n./f sha256={}\n",
+            SHA256, SHA256
+        );
+        let (algorithms, files) = parse(spec.as_bytes(), Path::new("/root")).unwrap();
+        assert_eq!(algorithms, vec![Algorithm::Sha256]);
+        let paths: Vec<&Path> = files.iter().map(FileMetadata::path).collect();
+        assert_eq!(paths, vec![Path::new("/root/d/e"), Path::new("/root/f")]);
+    }
+
+    #[test]
+    fn files_without_a_shared_digest_are_refused() {
+        let spec = format!("a sha256={}\nb sha1={}\n", SHA256, SHA1);
+        assert!(matches!(
+            parse(spec.as_bytes(), Path::new("/root")),
+            Err(Error::NoMtreeDigest(_))
+        ));
+        assert!(matches!(
+            parse(b"a sha256=xyz\n", Path::new("/root")),
+            Err(Error::InvalidMtreeLine(1))
+        ));
+    }
+
+    #[test]
+    fn times_count_nanoseconds_as_libarchive_writes_them() {
+        // bsdtar -c --format=mtree of a file modified at 1700000000.000000500
+        let spec = "#mtree\n./f time=1700000000.500 type=file sha256digest=98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4\n";
+        let (_, files) = parse(spec.as_bytes(), Path::new("/root")).unwrap();
+        let modified = files[0].modified().unwrap();
+        assert_eq!(
+            (modified.unix_timestamp(), modified.nanosecond()),
+            (1_700_000_000, 500)
+        );
+        assert_eq!(
+            parse_time("1700000000.000000500"),
+            parse_time("1700000000.500")
+        );
+        assert_eq!(parse_time("1.1000000000"), None);
+        assert_eq!(parse_time("1."), None);
+    }
+}
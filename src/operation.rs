@@ -1,11 +1,110 @@
+use crate::difference;
+use crate::hash::Algorithm;
 use crate::manifest::Id;
+use crate::output::Format;
+use crate::rules::Rules;
 use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum Operation {
-    Compare(Id, Id),
+    // Compare two manifests; the flags force the use of incomplete manifests
+    // and match entries by relative path even when the roots differ
+    Compare(Id, Id, bool, bool),
+    // Run the scans of a configuration file on their schedules, serving
+    // their status on a socket, by default the one next to the database
+    Daemon(PathBuf, Option<PathBuf>),
     DeleteManifest(Id),
-    Index(PathBuf),
+    // Write a manifest's entries, as checksum lines for a checksum format
+    Export(Id),
+    // Create a manifest from a checksum file and the root its paths are
+    // relative to
+    Import(PathBuf, PathBuf),
+    // Ask a running daemon, on the given socket or the default one, what its
+    // jobs are doing
+    Status(Option<PathBuf>),
+    // Scan a new root, hashing with every given algorithm
+    Index(PathBuf, Rules, Vec<Algorithm>),
     List,
-    Scan(Id),
+    // Continue a manifest's scan, skipping the paths it already recorded
+    Resume(Id),
+    // Rescan a manifest's root; unless the first flag forces a full rehash,
+    // the hashes of files whose stat is unchanged are carried forward. The
+    // second forces the use of an incomplete manifest
+    Scan(Id, bool, bool),
+    // Compare a manifest against the filesystem, storing a new manifest only
+    // when differences were found and the first flag is set. The second
+    // forces the use of an incomplete manifest
+    Verify(Id, bool, bool),
+    // Follow a manifest's root for changes, recording them when the first
+    // flag is set. The second forces the use of an incomplete manifest
+    Watch(Id, bool, bool),
+}
+
+/// Options that apply to every operation.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub format: Format,
+    // How many threads hash files during a scan
+    pub jobs: usize,
+}
+
+impl Settings {
+    /// Without an explicit job count, use one hashing thread per CPU.
+    pub fn new(format: Format, jobs: Option<usize>) -> Self {
+        let jobs = jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|jobs| jobs.get())
+                .unwrap_or(1)
+        });
+        Settings { format, jobs }
+    }
+}
+
+/// The exit code used when an operation fails outright.
+pub const FATAL_EXIT_CODE: u8 = 3;
+
+/// What a finished operation found, so callers such as cron jobs can tell
+/// the cases apart from the exit code alone.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    NoDifferences,
+    DifferencesFound,
+    // Some files could not be read but what could be read matched; drift
+    // among the readable files takes precedence
+    PartialScan,
+}
+
+impl Outcome {
+    pub fn new(differences_found: bool, unreadable: usize) -> Self {
+        if differences_found {
+            Outcome::DifferencesFound
+        } else if unreadable > 0 {
+            Outcome::PartialScan
+        } else {
+            Outcome::NoDifferences
+        }
+    }
+    /// A file that was recorded but can no longer be read is a gap in the
+    /// scan rather than drift, so it counts with the scan's own failures.
+    pub(crate) fn from_differences(differences: &[difference::Type], unreadable: usize) -> Self {
+        let gaps = differences
+            .iter()
+            .filter(|difference| matches!(difference, difference::Type::Unreadable(..)))
+            .count();
+        Outcome::new(differences.len() > gaps, unreadable + gaps)
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::NoDifferences => "no differences",
+            Outcome::DifferencesFound => "differences found",
+            Outcome::PartialScan => "partial scan",
+        }
+    }
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Outcome::NoDifferences => 0,
+            Outcome::DifferencesFound => 1,
+            Outcome::PartialScan => 2,
+        }
+    }
 }
@@ -0,0 +1,232 @@
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::difference::{self, LIVE_MANIFEST_ID};
+use crate::error::Error;
+use crate::escape::escape_path;
+use crate::filemetadata::FileMetadata;
+use crate::hash::Algorithm;
+use crate::manifest::Manifest;
+use crate::scanerror::ScanError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Ndjson,
+    // Checksum files and mtree specifications, only for export and import
+    B3sum,
+    Sha256sum,
+    Mtree,
+}
+
+impl Format {
+    /// Whether the format is for exporting and importing manifests rather
+    /// than for showing results.
+    pub fn exchange(&self) -> bool {
+        matches!(self, Format::B3sum | Format::Sha256sum | Format::Mtree)
+    }
+    /// The algorithm of a checksum file format.
+    pub fn checksum_algorithm(&self) -> Option<Algorithm> {
+        match self {
+            Format::B3sum => Some(Algorithm::Blake3),
+            Format::Sha256sum => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            "b3sum" => Ok(Format::B3sum),
+            "sha256sum" => Ok(Format::Sha256sum),
+            "mtree" => Ok(Format::Mtree),
+            _ => Err(Error::InvalidFormat(s.to_string())),
+        }
+    }
+}
+
+/// Something that can be printed as text or as a structured record. The
+/// field names of the records are stable so other tools can rely on them.
+pub trait Record: std::fmt::Display {
+    fn to_json(&self) -> Value;
+}
+
+fn time_to_json(time: &OffsetDateTime) -> Value {
+    match time.format(&Rfc3339) {
+        Ok(formatted) => Value::String(formatted),
+        Err(_) => Value::Null,
+    }
+}
+
+impl Record for FileMetadata {
+    fn to_json(&self) -> Value {
+        let stat = self.stat();
+        json!({
+            "path": escape_path(self.path()),
+            "hash": self.hash(),
+            "digests": self
+                .digests()
+                .iter()
+                .map(|(algorithm, digest)| (algorithm.as_str().to_string(), json!(digest)))
+                .collect::<serde_json::Map<String, Value>>(),
+            "kind": self.kind().as_str(),
+            "link_target": self.kind().link_target().map(escape_path),
+            "size": stat.map(|stat| stat.size()),
+            "mode": stat.map(|stat| stat.mode()),
+            "uid": stat.map(|stat| stat.uid()),
+            "gid": stat.map(|stat| stat.gid()),
+            "inode": stat.map(|stat| stat.inode()),
+            "device": stat.map(|stat| stat.device()),
+            "nlink": stat.map(|stat| stat.nlink()),
+            "changed": stat.and_then(|stat| stat.changed()).map(time_to_json),
+            "created": self.created().map(time_to_json),
+            "modified": self.modified().map(time_to_json),
+            "accessed": self.accessed().map(time_to_json),
+        })
+    }
+}
+
+impl Record for Manifest {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id().0,
+            "timestamp": self.timestamp().0,
+            "directory_path": escape_path(self.file_path()),
+            "state": self.state().as_str(),
+            "hash_algorithms": self
+                .algorithms()
+                .iter()
+                .map(|algorithm| algorithm.as_str())
+                .collect::<Vec<&str>>(),
+        })
+    }
+}
+
+impl Record for ScanError {
+    fn to_json(&self) -> Value {
+        json!({
+            "path": escape_path(self.path()),
+            "error": self.kind(),
+            "errno": self.errno(),
+        })
+    }
+}
+
+fn change_to_json(
+    kind: &str,
+    new_manifest: i64,
+    new: &dyn Record,
+    old_manifest: i64,
+    old: &dyn Record,
+) -> Value {
+    let manifest_id = |id: i64| {
+        if id == LIVE_MANIFEST_ID {
+            None
+        } else {
+            Some(id)
+        }
+    };
+    json!({
+        "type": kind,
+        "new_manifest_id": manifest_id(new_manifest),
+        "new": new.to_json(),
+        "old_manifest_id": manifest_id(old_manifest),
+        "old": old.to_json(),
+    })
+}
+
+impl Record for difference::Type {
+    fn to_json(&self) -> Value {
+        let kind = self.kind();
+        match self {
+            difference::Type::Add(file) | difference::Type::Delete(file) => {
+                json!({ "type": kind, "file": file.to_json() })
+            }
+            difference::Type::Hash(a, file_a, b, file_b)
+            | difference::Type::Target(a, file_a, b, file_b)
+            | difference::Type::Permission(a, file_a, b, file_b)
+            | difference::Type::Ownership(a, file_a, b, file_b)
+            | difference::Type::Size(a, file_a, b, file_b)
+            | difference::Type::Timestamp(a, file_a, b, file_b) => {
+                change_to_json(kind, *a, file_a, *b, file_b)
+            }
+            difference::Type::Unreadable(a, error, b, file) => {
+                change_to_json(kind, *a, error, *b, file)
+            }
+            difference::Type::Readable(a, file, b, error) => {
+                change_to_json(kind, *a, file, *b, error)
+            }
+            difference::Type::Moved { from, to } => json!({
+                "type": kind,
+                "from": from.to_json(),
+                "to": to.to_json(),
+            }),
+        }
+    }
+}
+
+/// Print every record in the requested format. `header` and `empty` are
+/// only used for text output.
+pub fn display_result<I, T>(format: Format, iterator: I, header: Option<&str>, empty: Option<&str>)
+where
+    I: Iterator<Item = T>,
+    T: Record,
+{
+    match format {
+        Format::Text | Format::B3sum | Format::Sha256sum | Format::Mtree => {
+            if let Some(header) = header {
+                println!("{}", header);
+            }
+            let mut iterator = iterator.peekable();
+            if iterator.peek().is_none() {
+                if let Some(empty) = empty {
+                    println!("{}", empty);
+                }
+            }
+            for item in iterator {
+                println!("{}", item);
+            }
+        }
+        Format::Json => {
+            let records: Vec<Value> = iterator.map(|item| item.to_json()).collect();
+            println!("{}", Value::Array(records));
+        }
+        Format::Ndjson => {
+            for item in iterator {
+                println!("{}", item.to_json());
+            }
+        }
+    }
+}
+
+/// Print the paths a scan could not read to stderr in the requested format,
+/// so that they are not missed but stay apart from the results on stdout.
+pub fn display_failures(format: Format, failures: &[ScanError]) {
+    if failures.is_empty() {
+        return;
+    }
+    match format {
+        Format::Text | Format::B3sum | Format::Sha256sum | Format::Mtree => {
+            eprintln!("Could not read:");
+            for failure in failures {
+                eprintln!("{}", failure);
+            }
+        }
+        Format::Json => {
+            let records: Vec<Value> = failures.iter().map(Record::to_json).collect();
+            eprintln!("{}", Value::Array(records));
+        }
+        Format::Ndjson => {
+            for failure in failures {
+                eprintln!("{}", failure.to_json());
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::error::Error;
+
+const IGNORE_FILE: &str = ".scannerignore";
+
+/// What the walk does when it meets a symbolic link.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SymlinkPolicy {
+    // Store the link itself, with its target, as an entry
+    #[default]
+    Record,
+    // Follow the link, never entering a directory that is its own ancestor
+    Follow,
+    // Leave links out entirely
+    Skip,
+}
+
+impl SymlinkPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SymlinkPolicy::Record => "record",
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::Skip => "skip",
+        }
+    }
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(SymlinkPolicy::Record),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(Error::InvalidSymlinkPolicy(s.to_string())),
+        }
+    }
+}
+
+/// How a manifest's tree was walked: the include and exclude patterns, both
+/// in gitignore syntax relative to the scan root, and the symlink policy.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules {
+    includes: Vec<String>,
+    excludes: Vec<String>,
+    symlinks: SymlinkPolicy,
+}
+
+impl Rules {
+    pub fn new(includes: Vec<String>, excludes: Vec<String>, symlinks: SymlinkPolicy) -> Self {
+        Rules {
+            includes,
+            excludes,
+            symlinks,
+        }
+    }
+    /// Append the patterns of the root's `.scannerignore`, if there is one,
+    /// to the excludes. Only the file at the root is read.
+    pub fn with_ignore_file(mut self, root: &Path) -> Result<Self, Error> {
+        let path = root.join(IGNORE_FILE);
+        if !path.is_file() {
+            return Ok(self);
+        }
+        for line in fs::read_to_string(&path)?.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.excludes.push(line.to_string());
+        }
+        Ok(self)
+    }
+    pub fn includes(&self) -> &[String] {
+        &self.includes
+    }
+    pub fn excludes(&self) -> &[String] {
+        &self.excludes
+    }
+    pub fn symlinks(&self) -> SymlinkPolicy {
+        self.symlinks
+    }
+    pub fn filter(&self, root: &Path) -> Result<Filter, Error> {
+        Ok(Filter {
+            includes: Rules::build(root, &self.includes)?,
+            excludes: Rules::build(root, &self.excludes)?,
+        })
+    }
+    fn build(root: &Path, patterns: &[String]) -> Result<Gitignore, Error> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            builder.add_line(None, pattern)?;
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Rules compiled against a scan root.
+#[derive(Clone)]
+pub struct Filter {
+    includes: Gitignore,
+    excludes: Gitignore,
+}
+
+impl Filter {
+    /// Whether the walk should descend into a directory. Excluded
+    /// directories are pruned without being read.
+    pub fn descend(&self, path: &Path) -> bool {
+        !self.excludes.matched(path, true).is_ignore()
+    }
+    /// Whether a file should be recorded. With no include patterns every
+    /// file that is not excluded is.
+    pub fn record(&self, path: &Path) -> bool {
+        if self.excludes.matched(path, false).is_ignore() {
+            return false;
+        }
+        self.includes.is_empty()
+            || self
+                .includes
+                .matched_path_or_any_parents(path, false)
+                .is_ignore()
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::escape::escape_path;
+
+/// A file or directory that a scan could not read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanError {
+    path: PathBuf,
+    // The name of the io::ErrorKind, such as PermissionDenied
+    kind: String,
+    errno: Option<i32>,
+}
+
+impl ScanError {
+    pub fn from_io_error(path: &Path, error: &io::Error) -> Self {
+        ScanError {
+            path: path.to_path_buf(),
+            kind: format!("{:?}", error.kind()),
+            errno: error.raw_os_error(),
+        }
+    }
+    pub fn from_error(path: &Path, error: &Error) -> Self {
+        match error {
+            Error::IO(error) => ScanError::from_io_error(path, error),
+            Error::NoFile(_) => ScanError::from_io_error(path, &io::ErrorKind::NotFound.into()),
+            _ => ScanError::from_io_error(path, &io::ErrorKind::Other.into()),
+        }
+    }
+    pub fn from_database(path: PathBuf, kind: String, errno: Option<i32>) -> Self {
+        ScanError { path, kind, errno }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+    pub fn errno(&self) -> Option<i32> {
+        self.errno
+    }
+    /// Whether the error hid `path`: either it is the path that failed, or
+    /// it lies below a directory that could not be read.
+    pub fn covers(&self, path: &Path) -> bool {
+        path.starts_with(&self.path)
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Path: {}, Error: {}", escape_path(&self.path), self.kind)?;
+        if let Some(errno) = self.errno {
+            write!(f, " (errno {})", errno)?;
+        }
+        Ok(())
+    }
+}
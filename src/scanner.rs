@@ -1,93 +1,313 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 use crate::error::Error;
 use crate::filemetadata::FileMetadata;
+use crate::hash::Algorithm;
+use crate::rules::{Filter, Rules, SymlinkPolicy};
+use crate::scanerror::ScanError;
+
+/// What an earlier manifest recorded, by path, so an unchanged file's hash
+/// can be reused. It is read before the scan starts, so the workers never
+/// wait on the database while the scan is being written to it.
+pub type Previous = Arc<HashMap<PathBuf, FileMetadata>>;
+
+/// The paths that are already recorded and need not be scanned again, read
+/// before the walk starts for the same reason as `Previous`.
+pub type Skip = Arc<HashSet<PathBuf>>;
+
+/// How many paths may wait for a hashing worker before the walk blocks.
+const QUEUE_DEPTH: usize = 4096;
 
 pub struct Scanner {
     root: PathBuf,
+    rules: Rules,
+    filter: Filter,
+    algorithms: Vec<Algorithm>,
+    jobs: usize,
+    previous: Option<Previous>,
+    skip: Option<Skip>,
 }
 
 enum Message {
     Path(PathBuf),
+    Link(PathBuf),
     File(FileMetadata),
-    Failure(PathBuf),
+    Failure(ScanError),
+}
+
+impl Message {
+    fn path(&self) -> &Path {
+        match self {
+            Message::Path(path) | Message::Link(path) => path,
+            Message::File(file) => file.path(),
+            Message::Failure(failure) => failure.path(),
+        }
+    }
+}
+
+/// Send a message on, failing if the other end has gone away.
+fn send(channel: &mpsc::SyncSender<Message>, message: Message) -> Result<(), Error> {
+    channel
+        .send(message)
+        .map_err(|e| Error::SendMessage(e.0.path().to_path_buf()))
 }
 
 impl Scanner {
-    pub fn new(root: PathBuf) -> Result<Scanner, Error> {
-        Ok(Scanner { root })
+    /// Files are hashed with every one of `algorithms`, or with the default
+    /// when none is given.
+    pub fn new(
+        root: PathBuf,
+        rules: Rules,
+        mut algorithms: Vec<Algorithm>,
+        jobs: usize,
+    ) -> Result<Scanner, Error> {
+        let filter = rules.filter(&root)?;
+        if algorithms.is_empty() {
+            algorithms.push(Algorithm::default());
+        }
+        Ok(Scanner {
+            root,
+            rules,
+            filter,
+            algorithms,
+            jobs: jobs.max(1),
+            previous: None,
+            skip: None,
+        })
+    }
+    /// Reuse the hashes of files that `previous` reports as unchanged.
+    pub fn with_previous(mut self, previous: Previous) -> Self {
+        self.previous = Some(previous);
+        self
+    }
+    /// Leave out, without hashing them, the files that `skip` reports.
+    pub fn with_skip(mut self, skip: Skip) -> Self {
+        self.skip = Some(skip);
+        self
     }
     pub fn root(&self) -> &Path {
         &self.root
     }
-    pub fn index(&self) -> Result<Vec<FileMetadata>, Error> {
-        let (file_send, file_receive) = mpsc::channel::<Message>();
-        let (main_send, main_receive) = mpsc::channel::<Message>();
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+    pub fn algorithms(&self) -> &[Algorithm] {
+        &self.algorithms
+    }
+    /// Walk and hash everything under the root. Alongside the files, return
+    /// the paths that could not be read. Both are sorted by path, so the
+    /// result does not depend on how many workers hashed it.
+    pub fn index(&self) -> Result<(Vec<FileMetadata>, Vec<ScanError>), Error> {
+        let mut scan = self.scan();
+        let mut files: Vec<FileMetadata> = scan.by_ref().collect();
+        let failures = scan.finish()?;
+        files.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok((files, failures))
+    }
+    /// Start walking and hashing in the background. The returned `Scan`
+    /// yields files as soon as they are hashed, and the queues between the
+    /// threads are bounded, so memory does not grow with the tree.
+    pub fn scan(&self) -> Scan {
+        let (file_send, file_receive) = mpsc::sync_channel::<Message>(QUEUE_DEPTH);
+        let file_receive = Arc::new(Mutex::new(file_receive));
+        let (main_send, main_receive) = mpsc::sync_channel::<Message>(QUEUE_DEPTH);
 
         let path = self.root.clone();
+        let filter = self.filter.clone();
+        let symlinks = self.rules.symlinks();
+        let skip = self.skip.clone();
         let scan_main_send = main_send.clone();
-        let scan_handle = thread::spawn(move || -> Result<(), Error> {
-            Scanner::visit_dir(&path, &file_send, &scan_main_send)?;
+        let mut handles = vec![thread::spawn(move || -> Result<(), Error> {
+            let walk = Walk {
+                filter: &filter,
+                symlinks,
+                skip: skip.as_ref(),
+                channel: &file_send,
+                main_sender: &scan_main_send,
+            };
+            walk.visit_dir(&path, &mut Vec::new())?;
             Ok(())
-        });
-
-        let hash_handle = thread::spawn(move || -> Result<(), Error> {
-            for message in file_receive {
-                if let Message::Path(path) = message {
-                    if let Ok(file) = FileMetadata::from_pathbuf(&path) {
-                        main_send.send(Message::File(file)).unwrap();
-                    } else {
-                        main_send.send(Message::Failure(path)).unwrap();
-                    }
+        })];
+
+        for _ in 0..self.jobs {
+            let file_receive = Arc::clone(&file_receive);
+            let main_send = main_send.clone();
+            let previous = self.previous.clone();
+            let algorithms = self.algorithms.clone();
+            handles.push(thread::spawn(move || -> Result<(), Error> {
+                Scanner::hash_worker(&file_receive, &main_send, &algorithms, previous.as_ref())
+            }));
+        }
+
+        Scan {
+            receiver: main_receive,
+            handles,
+            failures: Vec::new(),
+        }
+    }
+    fn hash_worker(
+        file_receive: &Mutex<mpsc::Receiver<Message>>,
+        main_send: &mpsc::SyncSender<Message>,
+        algorithms: &[Algorithm],
+        previous: Option<&Previous>,
+    ) -> Result<(), Error> {
+        loop {
+            // Hold the lock only while taking the next path, not while hashing
+            let message = match file_receive.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return Ok(()),
+            };
+            let (path, result) = match message {
+                Ok(Message::Path(path)) => {
+                    let prior = previous.and_then(|previous| previous.get(&path));
+                    let result = FileMetadata::from_pathbuf_with_previous(&path, algorithms, prior);
+                    (path, result)
                 }
+                Ok(Message::Link(path)) => {
+                    let result = FileMetadata::from_symlink(&path, algorithms);
+                    (path, result)
+                }
+                Ok(_) => continue,
+                Err(_) => return Ok(()),
+            };
+            match result {
+                Ok(file) => send(main_send, Message::File(file))?,
+                Err(e) => send(
+                    main_send,
+                    Message::Failure(ScanError::from_error(&path, &e)),
+                )?,
             }
-            Ok(())
-        });
+        }
+    }
+}
 
-        // XXX: gag
-        let maybe_error = scan_handle.join()?;
-        maybe_error?;
-        let maybe_error = hash_handle.join()?;
-        maybe_error?;
+/// A scan in progress, yielding each file once it has been hashed.
+pub struct Scan {
+    receiver: mpsc::Receiver<Message>,
+    handles: Vec<thread::JoinHandle<Result<(), Error>>>,
+    failures: Vec<ScanError>,
+}
 
-        let mut files: Vec<FileMetadata> = Vec::new();
-        for message in main_receive {
+impl Iterator for Scan {
+    type Item = FileMetadata;
+    fn next(&mut self) -> Option<FileMetadata> {
+        for message in self.receiver.iter() {
             if let Message::File(file) = message {
-                files.push(file);
-            } else if let Message::Failure(path) = message {
-                eprintln!("Could not create metadata for: {}", path.display());
+                return Some(file);
+            } else if let Message::Failure(failure) = message {
+                self.failures.push(failure);
             }
         }
-        Ok(files)
+        None
     }
-    fn visit_dir(
-        path: &Path,
-        channel: &mpsc::Sender<Message>,
-        main_sender: &mpsc::Sender<Message>,
-    ) -> Result<(), Error> {
-        if path.is_dir() {
-            let dir_iter = match fs::read_dir(path) {
-                Ok(readdir) => readdir,
-                Err(_) => {
-                    main_sender
-                        .send(Message::Failure(path.to_path_buf()))
-                        .unwrap();
-                    return Ok(());
+}
+
+impl Scan {
+    /// Wait for the walk and the workers to finish and return the paths
+    /// that could not be read, sorted. Files not yet taken are discarded.
+    pub fn finish(mut self) -> Result<Vec<ScanError>, Error> {
+        while self.next().is_some() {}
+        // XXX: gag
+        for handle in self.handles {
+            let maybe_error = handle.join()?;
+            maybe_error?;
+        }
+        self.failures.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(self.failures)
+    }
+}
+
+/// The state shared by every level of the directory walk.
+struct Walk<'a> {
+    filter: &'a Filter,
+    symlinks: SymlinkPolicy,
+    skip: Option<&'a Skip>,
+    channel: &'a mpsc::SyncSender<Message>,
+    main_sender: &'a mpsc::SyncSender<Message>,
+}
+
+impl Walk<'_> {
+    /// `ancestors` holds the device and inode of every directory above this
+    /// one, so that following a link back up the tree is not a loop.
+    fn visit_dir(&self, path: &Path, ancestors: &mut Vec<(u64, u64)>) -> Result<(), Error> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => metadata,
+            Ok(_) => return Ok(()),
+            Err(e) => return self.fail(path, &e),
+        };
+        let identity = (metadata.dev(), metadata.ino());
+        if ancestors.contains(&identity) {
+            return Ok(());
+        }
+        let dir_iter = match fs::read_dir(path) {
+            Ok(readdir) => readdir,
+            Err(e) => return self.fail(path, &e),
+        };
+        ancestors.push(identity);
+        for entry in dir_iter {
+            // An entry that cannot be read has no name of its own, so the
+            // failure is recorded against its directory
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.fail(path, &e)?;
+                    continue;
                 }
             };
-            for entry in dir_iter {
-                let entry = entry?;
-                let path = entry.path();
-                if path.is_file() {
-                    channel.send(Message::Path(path)).unwrap();
-                } else {
-                    Scanner::visit_dir(&path, channel, main_sender)?;
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    self.fail(&path, &e)?;
+                    continue;
                 }
+            };
+            if file_type.is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Record => {
+                        if self.wanted(&path) {
+                            send(self.channel, Message::Link(path))?;
+                        }
+                    }
+                    SymlinkPolicy::Follow => self.visit_path(path, ancestors)?,
+                    SymlinkPolicy::Skip => (),
+                }
+            } else {
+                self.visit_path(path, ancestors)?;
             }
         }
+        ancestors.pop();
         Ok(())
     }
+    fn visit_path(&self, path: PathBuf, ancestors: &mut Vec<(u64, u64)>) -> Result<(), Error> {
+        if path.is_file() {
+            if self.wanted(&path) {
+                send(self.channel, Message::Path(path))?;
+            }
+        } else if path.is_dir() {
+            if self.filter.descend(&path) {
+                self.visit_dir(&path, ancestors)?;
+            }
+        } else if let Err(e) = fs::metadata(&path) {
+            // A dangling link that was to be followed
+            self.fail(&path, &e)?;
+        }
+        Ok(())
+    }
+    /// Record a path that could not be read and carry on with the walk.
+    fn fail(&self, path: &Path, error: &io::Error) -> Result<(), Error> {
+        send(
+            self.main_sender,
+            Message::Failure(ScanError::from_io_error(path, error)),
+        )
+    }
+    fn wanted(&self, path: &Path) -> bool {
+        self.filter.record(path) && !self.skip.is_some_and(|skip| skip.contains(path))
+    }
 }
@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+use time::OffsetDateTime;
+
+use crate::error::Error;
+
+/// How far ahead to look for a minute a cron expression matches; a day
+/// such as February 30 never comes.
+const CRON_HORIZON_MINUTES: i64 = 5 * 366 * 24 * 60;
+
+/// When a daemon job runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    // At start, and again once the interval has passed since a run began
+    Every(Duration),
+    // At the minutes, in UTC, that a cron expression matches
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// The next run after a run that began at `last`, or right away when
+    /// there was none. Runs missed while another was going are not caught
+    /// up one by one.
+    pub fn next(&self, last: Option<OffsetDateTime>, now: OffsetDateTime) -> OffsetDateTime {
+        match self {
+            Schedule::Every(interval) => match last {
+                Some(last) => (last + *interval).max(now),
+                None => now,
+            },
+            Schedule::Cron(cron) => cron.next_after(now).unwrap_or(now),
+        }
+    }
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = Error;
+    /// Either `@every` with a count of `s`, `m`, `h` or `d`, one of the
+    /// `@hourly`, `@daily`, `@weekly` and `@monthly` shorthands, or five cron
+    /// fields.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidSchedule(s.to_string());
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let cron = match fields.as_slice() {
+            ["@every", interval] => {
+                return parse_interval(interval)
+                    .map(Schedule::Every)
+                    .ok_or_else(invalid)
+            }
+            ["@hourly"] => "0 * * * *",
+            ["@daily"] => "0 0 * * *",
+            ["@weekly"] => "0 0 * * 0",
+            ["@monthly"] => "0 0 1 * *",
+            [_, _, _, _, _] => s,
+            _ => return Err(invalid()),
+        };
+        let cron = Cron::parse(cron).ok_or_else(invalid)?;
+        if cron.next_after(OffsetDateTime::UNIX_EPOCH).is_none() {
+            return Err(invalid());
+        }
+        Ok(Schedule::Cron(cron))
+    }
+}
+
+fn parse_interval(interval: &str) -> Option<Duration> {
+    let split = interval.len().checked_sub(1)?;
+    let (count, unit) = interval.split_at(split);
+    let count: u64 = count.parse().ok()?;
+    let seconds = match unit {
+        "s" => count,
+        "m" => count.checked_mul(60)?,
+        "h" => count.checked_mul(60 * 60)?,
+        "d" => count.checked_mul(24 * 60 * 60)?,
+        _ => return None,
+    };
+    match seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
+/// The five fields of a cron expression, each as a bit set of the values
+/// it matches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // As in cron, when both day fields are restricted either may match
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    fn parse(expression: &str) -> Option<Cron> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Some(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+    fn matches(&self, time: OffsetDateTime) -> bool {
+        let bit = |set: u64, value: u8| set & (1 << value) != 0;
+        let day = bit(self.days, time.day());
+        let weekday = bit(self.weekdays, time.weekday().number_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && bit(self.minutes, time.minute())
+            && bit(self.hours, time.hour())
+            && bit(self.months, u8::from(time.month()))
+    }
+    /// The first whole minute after `time` that the expression matches.
+    fn next_after(&self, time: OffsetDateTime) -> Option<OffsetDateTime> {
+        let start = time.replace_second(0).ok()?.replace_nanosecond(0).ok()?;
+        (1..=CRON_HORIZON_MINUTES)
+            .map(|minutes| start + time::Duration::minutes(minutes))
+            .find(|candidate| self.matches(*candidate))
+    }
+}
+
+/// A comma separated list of `*`, a value or a range, each optionally
+/// followed by `/step`.
+fn parse_field(field: &str, min: u8, max: u8) -> Option<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u8>().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, value)
+                }
+            },
+        };
+        if first < min || last > max || first > last {
+            return None;
+        }
+        for value in (first..=last).step_by(usize::from(step)) {
+            set |= 1 << value;
+        }
+    }
+    Some(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Wednesday 2024-01-03 12:34:56 UTC
+    const WEDNESDAY: i64 = 1_704_285_296;
+
+    fn at(timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    fn next_after(expression: &str, timestamp: i64) -> i64 {
+        Cron::parse(expression)
+            .unwrap()
+            .next_after(at(timestamp))
+            .unwrap()
+            .unix_timestamp()
+    }
+
+    #[test]
+    fn weekly_runs_at_midnight_on_sunday() {
+        // Sunday 2024-01-07 00:00
+        assert_eq!(next_after("0 0 * * 0", WEDNESDAY), 1_704_585_600);
+        assert_eq!(next_after("0 0 * * 7", WEDNESDAY), 1_704_585_600);
+    }
+
+    #[test]
+    fn step_runs_on_the_next_multiple() {
+        // 12:45, then 13:00 as a match is always after the given time
+        assert_eq!(next_after("*/15 * * * *", WEDNESDAY), 1_704_285_900);
+        assert_eq!(next_after("*/15 * * * *", 1_704_285_900), 1_704_286_800);
+    }
+
+    #[test]
+    fn restricted_day_and_weekday_either_match() {
+        // Friday the 5th, Friday the 12th, then Saturday the 13th
+        assert_eq!(next_after("0 12 13 * 5", WEDNESDAY), 1_704_456_000);
+        assert_eq!(next_after("0 12 13 * 5", 1_704_456_000), 1_705_060_800);
+        assert_eq!(next_after("0 12 13 * 5", 1_705_060_800), 1_705_147_200);
+        // With the day of the month left open, only the weekday counts
+        assert_eq!(next_after("0 12 * * 5", 1_705_060_800), 1_705_665_600);
+    }
+
+    #[test]
+    fn fields_list_ranges_and_steps() {
+        let bits = |values: &[u8]| values.iter().fold(0, |set, value| set | 1 << value);
+        assert_eq!(
+            parse_field("1,3-5,10-20/5", 0, 59),
+            Some(bits(&[1, 3, 4, 5, 10, 15, 20]))
+        );
+        assert_eq!(parse_field("*/20", 0, 59), Some(bits(&[0, 20, 40])));
+        assert_eq!(parse_field("60", 0, 59), None);
+        assert_eq!(parse_field("5-3", 0, 59), None);
+        assert_eq!(parse_field("*/0", 0, 59), None);
+        assert_eq!(parse_field("0", 1, 31), None);
+    }
+
+    #[test]
+    fn schedules_parse() {
+        assert_eq!(
+            "@every 6h".parse::<Schedule>().unwrap(),
+            Schedule::Every(Duration::from_secs(6 * 60 * 60))
+        );
+        assert_eq!(
+            "@weekly".parse::<Schedule>().unwrap(),
+            "0 0 * * 0".parse::<Schedule>().unwrap()
+        );
+        assert!("@every 0s".parse::<Schedule>().is_err());
+        assert!("@every 6".parse::<Schedule>().is_err());
+        assert!("0 0 * *".parse::<Schedule>().is_err());
+        // February 30 never comes
+        assert!("0 0 30 2 *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn every_waits_for_the_interval_from_the_last_start() {
+        let schedule = Schedule::Every(Duration::from_secs(60 * 60));
+        let now = at(WEDNESDAY);
+        assert_eq!(schedule.next(None, now), now);
+        let last = at(WEDNESDAY - 10 * 60);
+        assert_eq!(schedule.next(Some(last), now), at(WEDNESDAY + 50 * 60));
+        // A run that overran its interval is followed straight away
+        let last = at(WEDNESDAY - 2 * 60 * 60);
+        assert_eq!(schedule.next(Some(last), now), now);
+    }
+}
@@ -1,8 +1,66 @@
 use crate::error::{Error, SchemaFileProblem};
 use std::collections::BTreeMap;
+#[cfg(test)]
 use std::fs;
 use std::path::Path;
 
+/// The numbered schema files, compiled into the binary so it does not depend
+/// on the working directory.
+const EMBEDDED_SCHEMAS: [(&str, &str); 13] = [
+    (
+        "1-create_manifest_table.sql",
+        include_str!("../schema/1-create_manifest_table.sql"),
+    ),
+    (
+        "2-create_entry_table.sql",
+        include_str!("../schema/2-create_entry_table.sql"),
+    ),
+    (
+        "3-add_entry_stat_columns.sql",
+        include_str!("../schema/3-add_entry_stat_columns.sql"),
+    ),
+    (
+        "4-create_manifest_rule_table.sql",
+        include_str!("../schema/4-create_manifest_rule_table.sql"),
+    ),
+    (
+        "5-add_symlink_columns.sql",
+        include_str!("../schema/5-add_symlink_columns.sql"),
+    ),
+    (
+        "6-add_entry_changed_column.sql",
+        include_str!("../schema/6-add_entry_changed_column.sql"),
+    ),
+    (
+        "7-add_manifest_state_column.sql",
+        include_str!("../schema/7-add_manifest_state_column.sql"),
+    ),
+    (
+        "8-create_scan_error_table.sql",
+        include_str!("../schema/8-create_scan_error_table.sql"),
+    ),
+    (
+        "9-store_paths_as_blobs.sql",
+        include_str!("../schema/9-store_paths_as_blobs.sql"),
+    ),
+    (
+        "10-store_relative_paths.sql",
+        include_str!("../schema/10-store_relative_paths.sql"),
+    ),
+    (
+        "11-add_hash_algorithm_columns.sql",
+        include_str!("../schema/11-add_hash_algorithm_columns.sql"),
+    ),
+    (
+        "12-create_watch_change_table.sql",
+        include_str!("../schema/12-create_watch_change_table.sql"),
+    ),
+    (
+        "13-add_manifest_scheduled_column.sql",
+        include_str!("../schema/13-add_manifest_scheduled_column.sql"),
+    ),
+];
+
 pub fn filename_u16(path: &Path) -> Result<u16, Error> {
     if let Some(os_name) = path.file_name() {
         if let Some(name) = os_name.to_str() {
@@ -24,13 +82,23 @@ pub fn filename_u16(path: &Path) -> Result<u16, Error> {
     }
 }
 
-pub fn read_schemas(schema_path: &Path) -> Result<Option<Vec<String>>, Error> {
+pub fn embedded_schemas() -> Result<Vec<(u16, String)>, Error> {
+    let mut schemas: BTreeMap<u16, String> = BTreeMap::new();
+    for (name, content) in EMBEDDED_SCHEMAS.iter() {
+        let value = filename_u16(Path::new(name))?;
+        schemas.insert(value, content.to_string());
+    }
+    Ok(schemas.into_iter().collect())
+}
+
+#[cfg(test)]
+pub fn read_schemas(schema_path: &Path) -> Result<Option<Vec<(u16, String)>>, Error> {
     if !schema_path.is_dir() {
         return Err(Error::InvalidSchemaDirectory(schema_path.to_path_buf()));
     }
 
     let mut schemas: BTreeMap<u16, String> = BTreeMap::new();
-    for entry in fs::read_dir(&schema_path)? {
+    for entry in fs::read_dir(schema_path)? {
         let entry = entry?;
         let path = entry.path();
         if path.is_file() {
@@ -39,9 +107,21 @@ pub fn read_schemas(schema_path: &Path) -> Result<Option<Vec<String>>, Error> {
             schemas.insert(value, content);
         }
     }
-    let contents: Vec<String> = schemas.into_values().collect();
+    let contents: Vec<(u16, String)> = schemas.into_iter().collect();
     if contents.is_empty() {
         return Ok(None);
     }
     Ok(Some(contents))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_schemas_match_schema_directory() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("schema");
+        let on_disk = read_schemas(&directory).unwrap().unwrap();
+        assert_eq!(embedded_schemas().unwrap(), on_disk);
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::difference::{self, LIVE_MANIFEST_ID};
+use crate::error::Error;
+use crate::filemetadata::FileMetadata;
+use crate::hash::Algorithm;
+use crate::rules::{Filter, Rules, SymlinkPolicy};
+use crate::scanerror::ScanError;
+
+/// How long a path must go without events before it is rehashed, so that a
+/// file written in several steps is reported once.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// How long to sleep between reads when no events are waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Events that can change what a manifest records for a path.
+const WATCH_MASK: WatchMask = WatchMask::ATTRIB
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::CREATE)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MODIFY)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO);
+
+/// Follows a manifest's root with inotify and compares each path that
+/// settles after an event against what was last known of it, starting from
+/// the manifest's entries.
+pub struct Watch {
+    inotify: Inotify,
+    directories: HashMap<WatchDescriptor, PathBuf>,
+    root: PathBuf,
+    filter: Filter,
+    symlinks: SymlinkPolicy,
+    algorithms: Vec<Algorithm>,
+    manifest_id: i64,
+    known: HashMap<PathBuf, FileMetadata>,
+    // Paths with events waiting out the debounce, by their latest event
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Watch {
+    pub fn new(
+        root: PathBuf,
+        rules: &Rules,
+        algorithms: Vec<Algorithm>,
+        manifest_id: i64,
+        known: Vec<FileMetadata>,
+    ) -> Result<Watch, Error> {
+        let mut watch = Watch {
+            inotify: Inotify::init()?,
+            directories: HashMap::new(),
+            filter: rules.filter(&root)?,
+            symlinks: rules.symlinks(),
+            algorithms,
+            manifest_id,
+            known: known
+                .into_iter()
+                .map(|file| (file.path().to_path_buf(), file))
+                .collect(),
+            pending: HashMap::new(),
+            root,
+        };
+        let root = watch.root.clone();
+        watch.add_directory(&root, false)?;
+        Ok(watch)
+    }
+    /// Wait for events until at least one path settles with a difference,
+    /// and return the differences of every path that settled.
+    pub fn next_differences(&mut self) -> Result<Vec<difference::Type>, Error> {
+        let mut buffer = [0; 4096];
+        loop {
+            let events: Vec<(WatchDescriptor, EventMask, Option<PathBuf>)> =
+                match self.inotify.read_events(&mut buffer) {
+                    Ok(events) => events
+                        .map(|event| (event.wd, event.mask, event.name.map(PathBuf::from)))
+                        .collect(),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Vec::new(),
+                    Err(e) => return Err(e.into()),
+                };
+            let idle = events.is_empty();
+            for (descriptor, mask, name) in events {
+                self.handle(descriptor, mask, name)?;
+            }
+            let differences = self.compare_settled();
+            if !differences.is_empty() {
+                return Ok(differences);
+            }
+            if idle {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+    fn handle(
+        &mut self,
+        descriptor: WatchDescriptor,
+        mask: EventMask,
+        name: Option<PathBuf>,
+    ) -> Result<(), Error> {
+        if mask.contains(EventMask::Q_OVERFLOW) {
+            // Events were lost, so every path has to be looked at again
+            let root = self.root.clone();
+            self.touch_known(&root);
+            return self.add_directory(&root, true);
+        }
+        if mask.contains(EventMask::IGNORED) {
+            self.directories.remove(&descriptor);
+            return Ok(());
+        }
+        let path = match (self.directories.get(&descriptor), name) {
+            (Some(directory), Some(name)) => directory.join(name),
+            _ => return Ok(()),
+        };
+        if !mask.contains(EventMask::ISDIR) {
+            // A link to a directory is not flagged as one, but is entered
+            // like one when links are followed
+            if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) && self.follows(&path) {
+                self.add_directory(&path, true)?;
+            } else if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                self.touch_known(&path);
+            }
+            self.pending.insert(path, Instant::now());
+        } else if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+            self.add_directory(&path, true)?;
+        } else if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+            self.touch_known(&path);
+        }
+        Ok(())
+    }
+    /// Watch a directory and those below it that the rules descend into,
+    /// marking the files found as touched when `touch` is set. A directory
+    /// that is gone by the time it is read is passed over, and one that may
+    /// not be read is reported and passed over, as a scan would.
+    fn add_directory(&mut self, path: &Path, touch: bool) -> Result<(), Error> {
+        self.add_directory_below(path, touch, &mut Vec::new())
+    }
+    /// `ancestors` holds the device and inode of every directory above this
+    /// one, so that following a link back up the tree is not a loop.
+    fn add_directory_below(
+        &mut self,
+        path: &Path,
+        touch: bool,
+        ancestors: &mut Vec<(u64, u64)>,
+    ) -> Result<(), Error> {
+        if !self.filter.descend(path) {
+            return Ok(());
+        }
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => return skip_path(path, e),
+        };
+        let identity = (metadata.dev(), metadata.ino());
+        if ancestors.contains(&identity) {
+            return Ok(());
+        }
+        let descriptor = match self.inotify.watches().add(path, WATCH_MASK) {
+            Ok(descriptor) => descriptor,
+            Err(e) => return skip_path(path, e),
+        };
+        self.directories.insert(descriptor, path.to_path_buf());
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => return skip_path(path, e),
+        };
+        ancestors.push(identity);
+        for entry in entries {
+            // An entry that cannot be read has no name of its own, so it is
+            // reported against its directory
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    skip_path(path, e)?;
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    skip_path(&path, e)?;
+                    continue;
+                }
+            };
+            if file_type.is_dir() || (file_type.is_symlink() && self.follows(&path)) {
+                self.add_directory_below(&path, touch, ancestors)?;
+            } else if touch {
+                self.pending.insert(path, Instant::now());
+            }
+        }
+        ancestors.pop();
+        Ok(())
+    }
+    /// Whether `path` is a link to a directory that the walk goes into.
+    fn follows(&self, path: &Path) -> bool {
+        self.symlinks == SymlinkPolicy::Follow && path.is_dir()
+    }
+    /// Mark every known path at or below `path` as touched.
+    fn touch_known(&mut self, path: &Path) {
+        let now = Instant::now();
+        for known in self.known.keys().filter(|known| known.starts_with(path)) {
+            self.pending.insert(known.clone(), now);
+        }
+    }
+    /// Rehash the paths that have settled and compare them with what was
+    /// last known. A path that cannot be read is reported as unreadable but
+    /// keeps its last known state.
+    fn compare_settled(&mut self) -> Vec<difference::Type> {
+        let now = Instant::now();
+        let settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, event)| now.duration_since(**event) >= DEBOUNCE)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut current = Vec::new();
+        let mut failures = Vec::new();
+        let mut previous = Vec::new();
+        for path in settled {
+            self.pending.remove(&path);
+            match self.describe(&path) {
+                Ok(file) => {
+                    previous.extend(self.known.remove(&path));
+                    current.extend(file);
+                }
+                Err(e) => {
+                    previous.extend(self.known.get(&path).cloned());
+                    failures.push(ScanError::from_error(&path, &e));
+                }
+            }
+        }
+        let differences = difference::compare(
+            LIVE_MANIFEST_ID,
+            &current,
+            &failures,
+            self.manifest_id,
+            previous,
+            &[],
+        );
+        for file in current {
+            self.known.insert(file.path().to_path_buf(), file);
+        }
+        differences
+    }
+    /// What a manifest would record for a path now, following the rules;
+    /// `None` when it would record nothing.
+    fn describe(&self, path: &Path) -> Result<Option<FileMetadata>, Error> {
+        if !self.filter.record(path) {
+            return Ok(None);
+        }
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.file_type().is_symlink() {
+            match self.symlinks {
+                SymlinkPolicy::Record => {
+                    FileMetadata::from_symlink(path, &self.algorithms).map(Some)
+                }
+                SymlinkPolicy::Follow if path.is_file() => {
+                    FileMetadata::from_pathbuf(path, &self.algorithms).map(Some)
+                }
+                _ => Ok(None),
+            }
+        } else if metadata.is_file() {
+            FileMetadata::from_pathbuf(path, &self.algorithms).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Pass over a path that is gone or may not be read, saying so for the
+/// latter; any other failure stops the watch.
+fn skip_path(path: &Path, error: io::Error) -> Result<(), Error> {
+    match error.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        io::ErrorKind::PermissionDenied => {
+            eprintln!(
+                "Could not watch: {}",
+                ScanError::from_io_error(path, &error)
+            );
+            Ok(())
+        }
+        _ => Err(error.into()),
+    }
+}